@@ -1,10 +1,24 @@
 use clap::{crate_authors, crate_description, crate_name, crate_version};
 use clap::{App, Arg, SubCommand, AppSettings};
-use std::net::{SocketAddr, TcpStream};
-use std::io::prelude::*;
-use std::str;
+use std::net::SocketAddr;
+use std::time::Duration;
 use kvs::*;
 
+/// keys asked for per request by `scan` and `keys`
+const SCAN_PAGE_SIZE: usize = 100;
+
+// exit codes by the class of error, 1 is for a command that did not take effect
+/// the server refused the request as malformed or not applicable to the value
+const EXIT_INVALID: i32 = 2;
+/// the request was valid but refused for now, by a conflict or a read-only server
+const EXIT_REFUSED: i32 = 3;
+/// the store on the server is damaged or cannot be read or written
+const EXIT_STORAGE: i32 = 4;
+/// anything else that went wrong on the server
+const EXIT_SERVER: i32 = 5;
+/// the server could not be reached
+const EXIT_CONNECTION: i32 = 6;
+
 fn main() {
     let matches = App::new(crate_name!()) //  env!("CARGO_PKG_NAME")
         .setting(AppSettings::ArgRequiredElseHelp)
@@ -15,41 +29,134 @@ fn main() {
         .subcommand(
             SubCommand::with_name("set")
                 .arg(Arg::with_name("KEY").index(1).required(true))
-                .arg(Arg::with_name("VALUE").index(2).required(true))
+                .arg(Arg::with_name("VALUE").index(2).required_unless("value-file"))
+                .arg(Arg::with_name("value-file").long("value-file").takes_value(true)
+                     .conflicts_with_all(&["VALUE", "nx", "xx", "ttl"])
+                     .help("--value-file PATH, set the bytes of the file as the value"))
+                .arg(Arg::with_name("nx").long("nx").conflicts_with("xx")
+                     .help("only set the key if it does not exist"))
+                .arg(Arg::with_name("xx").long("xx")
+                     .help("only set the key if it already exists"))
+                .arg(Arg::with_name("ttl").long("ttl").takes_value(true).conflicts_with_all(&["nx", "xx"])
+                     .help("--ttl SECONDS, remove the key once they have passed"))
                 .arg(
                     addr_arg()
                 ),
         )
         .subcommand(SubCommand::with_name("get")
                     .arg(Arg::with_name("KEY").required(true))
+                    .arg(Arg::with_name("value-file").long("value-file").takes_value(true)
+                         .help("--value-file PATH, write the value to the file instead of printing it"))
                     .arg(addr_arg()))
         .subcommand(SubCommand::with_name("rm")
                     .arg(Arg::with_name("KEY").required(true))
                     .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("ttl")
+                    .about("print the seconds KEY has left before it expires")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("persist")
+                    .about("clear the expiry of KEY")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("scan")
+                    .about("print the keys in ascending order with their values, one tab-separated pair per line")
+                    .arg(Arg::with_name("start").long("start").takes_value(true)
+                         .help("--start KEY, the first key"))
+                    .arg(Arg::with_name("end").long("end").takes_value(true)
+                         .help("--end KEY, the key to stop before"))
+                    .arg(Arg::with_name("prefix").long("prefix").takes_value(true)
+                         .help("--prefix PREFIX, only keys starting with it"))
+                    .arg(Arg::with_name("limit").long("limit").takes_value(true)
+                         .help("--limit N, print at most N keys"))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("keys")
+                    .about("print the keys matching PATTERN in ascending order, one per line")
+                    .arg(Arg::with_name("PATTERN")
+                         .help("glob pattern: * any characters, ? one character, [a-z] one of a set; * by default"))
+                    .arg(Arg::with_name("limit").long("limit").takes_value(true)
+                         .help("--limit N, print at most N keys"))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("count")
+                    .about("print the number of keys")
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("exists")
+                    .about("print whether KEY exists")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("compact")
+                    .about("reclaim the space of overwritten and removed values")
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("batch")
+                    .about("apply several sets and removes atomically, e.g. `batch set a 1 rm b`")
+                    .arg(Arg::with_name("OPS").multiple(true).required(true))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("cas")
+                    .about("set KEY to --new, or remove it without --new, only if it holds --expect, or is missing without --expect")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(Arg::with_name("expect").long("expect").takes_value(true))
+                    .arg(Arg::with_name("new").long("new").takes_value(true))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("incr")
+                    .setting(AppSettings::AllowNegativeNumbers)
+                    .about("add DELTA, 1 by default, to the integer stored at KEY and print the result")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(Arg::with_name("DELTA"))
+                    .arg(addr_arg()))
+        .subcommand(SubCommand::with_name("decr")
+                    .setting(AppSettings::AllowNegativeNumbers)
+                    .about("subtract DELTA, 1 by default, from the integer stored at KEY and print the result")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(Arg::with_name("DELTA"))
+                    .arg(addr_arg()))
         .get_matches();
     
     let mut addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
     
     if let Some(ref matches) = matches.subcommand_matches("set") {
-        let key = matches.value_of("KEY").unwrap();
-        let value = matches.value_of("VALUE").unwrap();
-
+        let key = matches.value_of("KEY").unwrap().to_owned();
         match_addr(&matches, &mut addr);
-        let command = Command::Set(key.to_owned(), value.to_owned());
-        let msg = serde_json::to_string(&command).unwrap();
-        call_server(&addr, &msg);
+
+        let command = if let Some(path) = matches.value_of("value-file"){
+            let value = std::fs::read(path).unwrap_or_else(|e| {
+                eprintln!("Cannot read {}: {}", path, e);
+                std::process::exit(1);
+            });
+            Command::SetBytes(Bytes(key.into_bytes()), Bytes(value))
+        }else{
+            let value = matches.value_of("VALUE").unwrap().to_owned();
+            set_command(&matches, key, value)
+        };
+        match call_server(&addr, &command){
+            Response::Error(e) => exit_with_error(e),
+            Response::Bool(false) => {
+                eprintln!("Key not set");
+                std::process::exit(1);
+            },
+            _ => {},
+        }
         return;
     }
 
     if let Some(ref matches) = matches.subcommand_matches("get") {
         let key = matches.value_of("KEY").unwrap();
         match_addr(&matches, &mut addr);
-        let command = Command::Get(key.to_owned());
-        let msg = serde_json::to_string(&command).unwrap();
-        let res = call_server(&addr, &msg);
-        match res{
-            Response::Error(ServerError::NotFound) => println!("Key not found"),
-            Response::Value(s) => println!("{}", s),
+        let path = match matches.value_of("value-file"){
+            Some(path) => path,
+            None => {
+                print_value(call_server(&addr, &Command::Get(key.to_owned())));
+                return;
+            },
+        };
+        match call_server(&addr, &Command::GetBytes(Bytes(key.as_bytes().to_vec()))){
+            Response::Error(ServerError{ code: ErrorCode::NotFound, .. }) => println!("Key not found"),
+            Response::Bytes(value) => {
+                if let Err(e) = std::fs::write(path, value.0){
+                    eprintln!("Cannot write {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            },
+            Response::Error(e) => exit_with_error(e),
             _ => {},
         }
         return;
@@ -59,29 +166,287 @@ fn main() {
         let key = matches.value_of("KEY").unwrap();
         match_addr(&matches, &mut addr);
         let command = Command::Rm(key.to_owned());
-        let msg = serde_json::to_string(&command).unwrap();
-        let res = call_server(&addr, &msg);
-        match res{
-            Response::Error(ServerError::NotFound) => {
-                eprintln!("Key not found");
+        let res = call_server(&addr, &command);
+        if let Response::Error(e) = res{
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("ttl") {
+        let key = matches.value_of("KEY").unwrap();
+        match_addr(&matches, &mut addr);
+        match call_server(&addr, &Command::Ttl(key.to_owned())){
+            Response::Error(ServerError{ code: ErrorCode::NotFound, .. }) => println!("Key not found"),
+            Response::Ttl(Some(ttl)) => println!("{:.3}", ttl.as_secs_f64()),
+            Response::Ttl(None) => println!("No expiry"),
+            Response::Error(e) => exit_with_error(e),
+            _ => {},
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("persist") {
+        let key = matches.value_of("KEY").unwrap();
+        match_addr(&matches, &mut addr);
+        if let Response::Error(e) = call_server(&addr, &Command::Persist(key.to_owned())){
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("scan") {
+        let mut request = ScanRequest{
+            start: matches.value_of("start").map(str::to_owned),
+            end: matches.value_of("end").map(str::to_owned),
+            prefix: matches.value_of("prefix").map(str::to_owned),
+            limit: SCAN_PAGE_SIZE,
+        };
+        let mut left = match_limit(&matches);
+        match_addr(&matches, &mut addr);
+
+        let mut client = connect(&addr);
+        while left > 0{
+            request.limit = left.min(SCAN_PAGE_SIZE);
+            let res = client.request(&Command::Scan(request.clone()))
+                .unwrap_or_else(exit_on_request_error);
+            match res{
+                Response::Page(entries, next) => {
+                    left -= entries.len();
+                    for (key, value) in entries{
+                        println!("{}\t{}", key, value);
+                    }
+                    match next{
+                        Some(next) => request.start = Some(next),
+                        None => break,
+                    }
+                },
+                Response::Error(e) => exit_with_error(e),
+                _ => break,
+            }
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("keys") {
+        let mut request = KeysRequest{
+            pattern: matches.value_of("PATTERN").unwrap_or("*").to_owned(),
+            start: None,
+            limit: SCAN_PAGE_SIZE,
+        };
+        let mut left = match_limit(&matches);
+        match_addr(&matches, &mut addr);
+
+        let mut client = connect(&addr);
+        while left > 0{
+            request.limit = left.min(SCAN_PAGE_SIZE);
+            let res = client.request(&Command::Keys(request.clone()))
+                .unwrap_or_else(exit_on_request_error);
+            match res{
+                Response::Keys(keys, next) => {
+                    left -= keys.len();
+                    for key in keys{
+                        println!("{}", key);
+                    }
+                    match next{
+                        Some(next) => request.start = Some(next),
+                        None => break,
+                    }
+                },
+                Response::Error(e) => exit_with_error(e),
+                _ => break,
+            }
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("count") {
+        match_addr(&matches, &mut addr);
+        print_integer(call_server(&addr, &Command::Count));
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("exists") {
+        let key = matches.value_of("KEY").unwrap();
+        match_addr(&matches, &mut addr);
+        match call_server(&addr, &Command::Exists(key.to_owned())){
+            Response::Bool(exists) => println!("{}", exists),
+            Response::Error(e) => exit_with_error(e),
+            _ => {},
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("compact") {
+        match_addr(&matches, &mut addr);
+        let res = call_server(&addr, &Command::Compact);
+        if let Response::Error(e) = res{
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("batch") {
+        let batch = parse_batch(matches.values_of("OPS").unwrap().collect());
+        match_addr(&matches, &mut addr);
+        let res = call_server(&addr, &Command::Batch(batch));
+        if let Response::Error(e) = res{
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("cas") {
+        let key = matches.value_of("KEY").unwrap().to_owned();
+        let expected = matches.value_of("expect").map(str::to_owned);
+        let new = matches.value_of("new").map(str::to_owned);
+        match_addr(&matches, &mut addr);
+        match call_server(&addr, &Command::Cas(key, expected, new)){
+            Response::Error(e) => exit_with_error(e),
+            Response::Bool(false) => {
+                eprintln!("Value mismatch");
                 std::process::exit(1);
             },
             _ => {},
         }
         return;
     }
+
+    if let Some(ref matches) = matches.subcommand_matches("incr") {
+        let key = matches.value_of("KEY").unwrap().to_owned();
+        let delta = match_delta(&matches);
+        match_addr(&matches, &mut addr);
+        print_integer(call_server(&addr, &Command::Incr(key, delta)));
+        return;
+    }
+
+    if let Some(ref matches) = matches.subcommand_matches("decr") {
+        let key = matches.value_of("KEY").unwrap().to_owned();
+        let delta = match_delta(&matches);
+        match_addr(&matches, &mut addr);
+        print_integer(call_server(&addr, &Command::Decr(key, delta)));
+    }
+}
+
+/// parse "set KEY VALUE" and "rm KEY" operations, in any number
+fn parse_batch(args: Vec<&str>) -> WriteBatch{
+    let mut batch = WriteBatch::new();
+    let mut args = args.into_iter();
+    while let Some(op) = args.next(){
+        match (op, args.next()){
+            ("set", Some(key)) => match args.next(){
+                Some(value) => batch.set(key.to_owned(), value.to_owned()),
+                None => {
+                    eprintln!("Missing value for key {}", key);
+                    std::process::exit(1);
+                }
+            },
+            ("rm", Some(key)) => batch.remove(key.to_owned()),
+            _ => {
+                eprintln!("Invalid batch operation {}, expected \"set KEY VALUE\" or \"rm KEY\"", op);
+                std::process::exit(1);
+            }
+        }
+    }
+    batch
 }
 
-fn call_server(addr: &SocketAddr, msg: &str) -> Response{
-    let mut stream = TcpStream::connect(addr)
-                    .unwrap_or_else(|e| panic!("connect to server failed: {}", e));
-    stream.write(msg.as_bytes()).unwrap();
+fn call_server(addr: &SocketAddr, command: &Command) -> Response{
+    let mut client = connect(addr);
+    client.request(command).unwrap_or_else(exit_on_request_error)
+}
 
-    let mut buf = [0; 512];
-    
-    let len = stream.read(&mut buf).unwrap();
-    let response: Response = serde_json::from_slice(&buf[0..len]).unwrap(); 
-    response
+fn connect(addr: &SocketAddr) -> KvsClient{
+    KvsClient::connect(addr).unwrap_or_else(|e| {
+        eprintln!("Cannot connect to {}: {}", addr, e);
+        std::process::exit(EXIT_CONNECTION);
+    })
+}
+
+fn exit_on_request_error<T>(e: KvsError) -> T{
+    eprintln!("Request failed: {}", e);
+    std::process::exit(EXIT_CONNECTION);
+}
+
+fn exit_with_error(e: ServerError) -> !{
+    eprintln!("{}", e);
+    if e.retryable{
+        eprintln!("The request may succeed if tried again");
+    }
+    if e.code == ErrorCode::NotUtf8{
+        eprintln!("Get it with --value-file");
+    }
+    std::process::exit(match e.code{
+        ErrorCode::NotFound => 1,
+        ErrorCode::InvalidCommand | ErrorCode::FrameTooLarge | ErrorCode::NotAnInteger
+        | ErrorCode::Overflow | ErrorCode::NotUtf8 => EXIT_INVALID,
+        ErrorCode::Conflict | ErrorCode::ReadOnly => EXIT_REFUSED,
+        ErrorCode::Corrupted | ErrorCode::StorageFull | ErrorCode::Storage => EXIT_STORAGE,
+        ErrorCode::Internal => EXIT_SERVER,
+    });
+}
+
+/// the command for `set` without --value-file, as the conditions ask
+fn set_command(matches: &clap::ArgMatches, key: String, value: String) -> Command{
+    if matches.is_present("nx"){
+        Command::SetIfAbsent(key, value)
+    }else if matches.is_present("xx"){
+        Command::SetIfPresent(key, value)
+    }else if matches.is_present("ttl"){
+        Command::SetWithTtl(key, value, match_ttl(matches))
+    }else{
+        Command::Set(key, value)
+    }
+}
+
+fn print_value(res: Response){
+    match res{
+        Response::Error(ServerError{ code: ErrorCode::NotFound, .. }) => println!("Key not found"),
+        Response::Value(s) => println!("{}", s),
+        Response::Error(e) => exit_with_error(e),
+        _ => {},
+    }
+}
+
+fn print_integer(res: Response){
+    match res{
+        Response::Integer(value) => println!("{}", value),
+        Response::Error(e) => exit_with_error(e),
+        _ => {},
+    }
+}
+
+fn match_ttl(matches: &clap::ArgMatches) -> Duration{
+    let secs = matches.value_of("ttl").unwrap().parse::<f64>().ok().filter(|&secs| secs > 0.0);
+    match secs.map(Duration::try_from_secs_f64){
+        Some(Ok(ttl)) => ttl,
+        _ => {
+            eprintln!("Invalid ttl, expected a positive number of seconds");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn match_delta(matches: &clap::ArgMatches) -> i64{
+    match matches.value_of("DELTA").map(str::parse).unwrap_or(Ok(1)){
+        Ok(delta) => delta,
+        Err(_) => {
+            eprintln!("Invalid delta, expected a 64-bit integer");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// the --limit of a listing, no limit if it is missing
+fn match_limit(matches: &clap::ArgMatches) -> usize{
+    match matches.value_of("limit").map(str::parse){
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            eprintln!("Invalid limit");
+            std::process::exit(1);
+        },
+        None => usize::MAX,
+    }
 }
 
 fn match_addr(matches: &clap::ArgMatches, addr:&mut std::net::SocketAddr){
@@ -3,18 +3,22 @@ use std::net::{
     TcpListener,
     TcpStream
 };
-use std::io::{
-    Write,
-    prelude::*,
-};
+use std::convert::TryFrom;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
 
-use log::{info, error};
+use log::{info, warn, error};
 use clap::{crate_authors, crate_description, crate_name, crate_version};
 use clap::{App, Arg, AppSettings};
-use sled;
 
-use kvs::{Engine,Command,Response, ServerError,KvsError,KvsEngine, KvStore, SledStore};
+use kvs::{Engine,Pool,Command,Response, ErrorCode, ServerError,KvsError,KvsEngine, KvStore, SledStore, CompactionPolicy, Durability};
+use kvs::{BatchOp, Bytes, Glob, KeysRequest, ScanRequest, Transaction};
+use kvs::thread_pool::{ThreadPool, NaiveThreadPool, SharedQueueThreadPool, RayonThreadPool};
+use kvs::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE};
 
 fn main() { 
     kvs::log_init();
@@ -40,7 +44,22 @@ fn main() {
         }
     }
 
-    let server = Server::new(addr, engine);
+    let config = ConnectionConfig{
+        max_frame_size: match_max_frame_size(&matches),
+        idle_timeout: match_idle_timeout(&matches),
+        max_requests: match_max_requests(&matches),
+        max_connections: match_max_connections(&matches),
+        read_only: matches.is_present("read-only"),
+    };
+
+    let pool = match_pool(&matches);
+    let threads = match_threads(&matches);
+    let compaction_policy = match_compaction_policy(&matches);
+    let durability = match_durability(&matches);
+    let group_commit = match_group_commit(&matches);
+    let max_segment_size = match_max_segment_size(&matches);
+
+    let server = Server::new(addr, engine, pool, threads, config, compaction_policy, durability, group_commit, max_segment_size);
     server.run();
 }
 
@@ -66,6 +85,81 @@ fn get_cli_mathces() -> clap::ArgMatches<'static>{
             .help("the ENGINE-NAME is either \"kvs\" or \"sled\"")
             .long("engine")
         )
+        .arg(
+            Arg::with_name("max-frame-size")
+            .takes_value(true)
+            .multiple(false)
+            .help("--max-frame-size BYTES, the largest request the server accepts")
+            .long("max-frame-size")
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+            .takes_value(true)
+            .multiple(false)
+            .help("--idle-timeout SECONDS, close connections idle for longer, 0 disables it")
+            .long("idle-timeout")
+        )
+        .arg(
+            Arg::with_name("max-requests")
+            .takes_value(true)
+            .multiple(false)
+            .help("--max-requests N, close a connection after serving N requests")
+            .long("max-requests")
+        )
+        .arg(
+            Arg::with_name("max-connections")
+            .takes_value(true)
+            .multiple(false)
+            .help("--max-connections N, serve at most N connections at once, later ones wait to be accepted")
+            .long("max-connections")
+        )
+        .arg(
+            Arg::with_name("pool")
+            .takes_value(true)
+            .multiple(false)
+            .help("--pool POOL-NAME, either \"naive\", \"shared\" or \"rayon\"")
+            .long("pool")
+        )
+        .arg(
+            Arg::with_name("threads")
+            .takes_value(true)
+            .multiple(false)
+            .help("--threads N, the number of worker threads, defaults to the number of cpus")
+            .long("threads")
+        )
+        .arg(
+            Arg::with_name("compaction")
+            .takes_value(true)
+            .multiple(false)
+            .help("--compaction POLICY, one of \"manual\", \"ratio:RATIO\", \"bytes:BYTES\" or \"interval:SECONDS\", kvs engine only")
+            .long("compaction")
+        )
+        .arg(
+            Arg::with_name("durability")
+            .takes_value(true)
+            .multiple(false)
+            .help("--durability MODE, when writes are synced to disk: \"always\", \"every:N\" writes, \"interval:MILLISECONDS\" or \"os\"")
+            .long("durability")
+        )
+        .arg(
+            Arg::with_name("group-commit")
+            .takes_value(true)
+            .multiple(false)
+            .help("--group-commit MILLISECONDS, commit concurrent writes in batches waiting at most this long for a batch to fill, kvs engine only")
+            .long("group-commit")
+        )
+        .arg(
+            Arg::with_name("max-segment-size")
+            .takes_value(true)
+            .multiple(false)
+            .help("--max-segment-size BYTES, start a new log once the active one grew this long, kvs engine only")
+            .long("max-segment-size")
+        )
+        .arg(
+            Arg::with_name("read-only")
+            .help("--read-only, serve the store without ever changing it and reject writes, kvs engine only")
+            .long("read-only")
+        )
         .get_matches()
 }
 
@@ -94,6 +188,136 @@ fn match_engine(matches: &clap::ArgMatches) -> Option<Engine> {
     }
 }
 
+fn match_max_frame_size(matches: &clap::ArgMatches) -> usize {
+    match matches.value_of("max-frame-size"){
+        None => DEFAULT_MAX_FRAME_SIZE,
+        Some(s) => match s.parse(){
+            Ok(v) if v > 0 => v,
+            _ => {
+                eprintln!("Invalid max frame size, see help.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn match_idle_timeout(matches: &clap::ArgMatches) -> Option<Duration> {
+    match matches.value_of("idle-timeout"){
+        None => Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS)),
+        Some(s) => match s.parse(){
+            Ok(0) => None,
+            Ok(v) => Some(Duration::from_secs(v)),
+            Err(_) => {
+                eprintln!("Invalid idle timeout, see help.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn match_max_requests(matches: &clap::ArgMatches) -> Option<usize> {
+    match matches.value_of("max-requests"){
+        None => None,
+        Some(s) => match s.parse(){
+            Ok(v) if v > 0 => Some(v),
+            _ => {
+                eprintln!("Invalid max requests, see help.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn match_max_connections(matches: &clap::ArgMatches) -> usize {
+    match matches.value_of("max-connections"){
+        None => DEFAULT_MAX_CONNECTIONS,
+        Some(s) => match s.parse(){
+            Ok(v) if v > 0 => v,
+            _ => {
+                eprintln!("Invalid max connections, see help.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn match_pool(matches: &clap::ArgMatches) -> Pool {
+    match matches.value_of("pool"){
+        None | Some("shared") => Pool::SharedQueue,
+        Some("naive") => Pool::Naive,
+        Some("rayon") => Pool::Rayon,
+        _ => {
+            eprintln!("Invalid pool value, see help.");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn match_threads(matches: &clap::ArgMatches) -> u32 {
+    match matches.value_of("threads"){
+        None => num_cpus::get() as u32,
+        Some(s) => match s.parse(){
+            Ok(v) if v > 0 => v,
+            _ => {
+                eprintln!("Invalid threads value, see help.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn match_compaction_policy(matches: &clap::ArgMatches) -> CompactionPolicy {
+    match matches.value_of("compaction"){
+        None => CompactionPolicy::default(),
+        Some(s) => s.parse().unwrap_or_else(|e| {
+            eprintln!("{}, see help.", e);
+            std::process::exit(1);
+        }),
+    }
+}
+
+fn match_durability(matches: &clap::ArgMatches) -> Durability {
+    match matches.value_of("durability"){
+        None => Durability::default(),
+        Some(s) => s.parse().unwrap_or_else(|e| {
+            eprintln!("{}, see help.", e);
+            std::process::exit(1);
+        }),
+    }
+}
+
+fn match_group_commit(matches: &clap::ArgMatches) -> Option<Duration> {
+    match matches.value_of("group-commit"){
+        None => None,
+        Some(s) => match s.parse(){
+            Ok(v) => Some(Duration::from_millis(v)),
+            Err(_) => {
+                eprintln!("Invalid group commit delay, see help.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn match_max_segment_size(matches: &clap::ArgMatches) -> Option<u64> {
+    match matches.value_of("max-segment-size"){
+        None => None,
+        Some(s) => match s.parse(){
+            Ok(v) if v > 0 => Some(v),
+            _ => {
+                eprintln!("Invalid max segment size, see help.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// log why the engine could not be opened, a store locked by another process among others, and exit
+fn exit_on_open_error<E>(e: KvsError) -> E{
+    error!("failed to open the engine: {}", e);
+    std::process::exit(1);
+}
+
 fn engine_file_exists() -> Option<Engine>{
     let path = PathBuf::from("./kvstore");
     if path.exists() && path.is_dir(){
@@ -108,102 +332,598 @@ fn engine_file_exists() -> Option<Engine>{
     return None;
 }
 
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+
+/// most connections served at once unless `--max-connections` says otherwise, each takes a thread
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// most keys in one page of a scan, whatever the client asks for
+const MAX_SCAN_PAGE: usize = 1000;
+
+/// most keys looked at for one page of `Command::Keys`, matching or not
+const MAX_KEYS_EXAMINED: usize = 10_000;
+
+/// limits applied to every client connection
+struct ConnectionConfig{
+    max_frame_size: usize,
+    /// None means a connection may stay idle forever
+    idle_timeout: Option<Duration>,
+    /// None means no limit
+    max_requests: Option<usize>,
+    /// most connections served at once, the others wait in the listen backlog
+    max_connections: usize,
+    /// reject every command that would change the store
+    read_only: bool,
+}
+
 struct Server{
     addr: SocketAddr,
     engine: Engine,
+    pool: Pool,
+    threads: u32,
+    config: Arc<ConnectionConfig>,
+    compaction_policy: CompactionPolicy,
+    durability: Durability,
+    /// max batch delay, None turns group commit off
+    group_commit: Option<Duration>,
+    /// None keeps the engine's default
+    max_segment_size: Option<u64>,
 }
 
 impl Server{
-    pub fn new(addr: SocketAddr, engine: Engine) -> Self{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(addr: SocketAddr, engine: Engine, pool: Pool, threads: u32, config: ConnectionConfig,
+               compaction_policy: CompactionPolicy, durability: Durability, group_commit: Option<Duration>,
+               max_segment_size: Option<u64>) -> Self{
         Server{
             addr,
             engine,
+            pool,
+            threads,
+            config: Arc::new(config),
+            compaction_policy,
+            durability,
+            group_commit,
+            max_segment_size,
         }
     }
 
     pub fn run(&self) {
         info!("starting server, version: {}", crate_version!());
         info!("server started at {}, engine: {:?}", self.addr, self.engine);
+        info!("thread pool: {:?}, threads: {}", self.pool, self.threads);
+        info!("durability: {:?}", self.durability);
         
        match self.engine{
             Engine::Kvs => {
-                let mut engine = KvStore::open("kvstore").unwrap();
-                self.handle_with_engine(&mut engine);
+                info!("compaction policy: {:?}", self.compaction_policy);
+                let mut builder = KvStore::builder("kvstore")
+                                .compaction_policy(self.compaction_policy)
+                                .durability(self.durability);
+                if let Some(max_delay) = self.group_commit{
+                    info!("group commit, max batch delay: {:?}", max_delay);
+                    builder = builder.group_commit(max_delay);
+                }
+                if let Some(max_segment_size) = self.max_segment_size{
+                    info!("max segment size: {} bytes", max_segment_size);
+                    builder = builder.max_segment_size(max_segment_size);
+                }
+                let engine = if self.config.read_only{
+                    info!("read-only");
+                    builder.open_read_only()
+                }else{
+                    builder.open()
+                };
+                self.run_with_engine(engine.unwrap_or_else(exit_on_open_error));
+            },
+
+            Engine::Sled if self.config.read_only => {
+                error!("the sled engine cannot be served read-only");
+                std::process::exit(1);
             },
 
             Engine::Sled => {
-                let mut engine = SledStore::new(sled::open("sled_store").unwrap());
-                self.handle_with_engine(&mut engine);
+                let engine = SledStore::open("sled_store", self.durability).unwrap_or_else(exit_on_open_error);
+                self.run_with_engine(engine);
+            }
+        };
+    }
+
+    fn run_with_engine<E: KvsEngine>(&self, engine: E){
+        match self.pool{
+            Pool::Naive => self.handle_with_engine::<E, NaiveThreadPool>(engine),
+            Pool::SharedQueue => self.handle_with_engine::<E, SharedQueueThreadPool>(engine),
+            Pool::Rayon => self.handle_with_engine::<E, RayonThreadPool>(engine),
+        }
+    }
+
+    /// every connection waits for its requests on a thread of its own, so idle connections
+    /// do not hold up the pool; only running a command takes one of its workers.
+    /// once `max_connections` are open, the next one is only accepted after one of them closed
+    fn handle_with_engine<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(&self, engine: E){
+        let pool = Arc::new(P::new(self.threads).unwrap_or_else(|e| {
+            error!("create thread pool failed: {}", e);
+            std::process::exit(1);
+        }));
+        let listener = TcpListener::bind(self.addr).unwrap_or_else(|e| {
+            error!("bind {} failed: {}", self.addr, e);
+            std::process::exit(1);
+        });
+        let slots = Arc::new(ConnectionSlots{
+            open: Mutex::new(0),
+            freed: Condvar::new(),
+            max: self.config.max_connections,
+        });
+
+        loop{
+            let slot = ConnectionSlots::acquire(&slots);
+            let stream = match listener.accept(){
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("accept connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let engine = engine.clone();
+            let pool = Arc::clone(&pool);
+            let config = Arc::clone(&self.config);
+            let spawned = thread::Builder::new()
+                .name("kvs-connection".to_owned())
+                .spawn(move || {
+                    if let Err(e) = serve(engine, &*pool, &config, stream){
+                        error!("serve connection failed: {}", e);
+                    }
+                    drop(slot);
+                });
+            if let Err(e) = spawned{
+                error!("spawn connection thread failed: {}", e);
             }
+        }
+    }
+}
+
+/// counts the connections being served, so no more than `max` hold a thread at once
+struct ConnectionSlots{
+    open: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+/// a connection being served, frees its slot when dropped
+struct ConnectionSlot(Arc<ConnectionSlots>);
+
+impl ConnectionSlots{
+    /// wait until fewer than `max` connections are open and take a slot
+    fn acquire(slots: &Arc<ConnectionSlots>) -> ConnectionSlot{
+        // a panic never happens while the count is held, the count stays right
+        let mut open = slots.open.lock().unwrap_or_else(PoisonError::into_inner);
+        if *open >= slots.max{
+            warn!("{} connections open, waiting for one to close", open);
+        }
+        while *open >= slots.max{
+            open = slots.freed.wait(open).unwrap_or_else(PoisonError::into_inner);
+        }
+        *open += 1;
+        ConnectionSlot(Arc::clone(slots))
+    }
+}
+
+impl Drop for ConnectionSlot{
+    fn drop(&mut self){
+        let mut open = self.0.open.lock().unwrap_or_else(PoisonError::into_inner);
+        *open -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// what a connection keeps between its commands: its own clone of the engine, whose reads keep their
+/// file handles from one command to the next, and its open transaction, dropped if the client hangs up
+struct Session<E: KvsEngine>{
+    engine: E,
+    txn: Option<Transaction<E>>,
+}
+
+/// serve requests from one connection until the client hangs up,
+/// goes idle for too long or reaches the request limit
+fn serve<E: KvsEngine, P: ThreadPool>(engine: E, pool: &P, config: &ConnectionConfig, stream: TcpStream) -> kvs::Result<()>{
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(config.idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut served = 0;
+    let mut session = Session{ engine, txn: None };
+
+    loop{
+        let command: kvs::Result<Option<Command>> = read_frame(&mut reader, config.max_frame_size);
+
+        let response = match command{
+            Ok(Some(op)) if config.read_only && op.is_write() => Response::Error(ErrorCode::ReadOnly.into()),
+            Ok(Some(op)) => {
+                let (returned, response) = run_command(pool, session, op)?;
+                session = returned;
+                response
+            },
+            Ok(None) => break,
+            Err(e @ KvsError::FrameTooLarge(..)) => {
+                error!("request from {} refused: {}", peer, e);
+                Response::Error(e.into())
+            },
+            // a request that does not decode; a serde error of the engine is not the client's fault
+            Err(e @ KvsError::Serde(_)) => Response::Error(ServerError::new(ErrorCode::InvalidCommand, e.to_string())),
+            Err(KvsError::Io(ref e)) if is_timeout(e) => {
+                info!("closing idle connection from {}", peer);
+                break;
+            },
+            Err(e) => return Err(e),
         };
+
+        write_frame(&mut writer, &response)?;
+        served += 1;
+
+        if matches!(config.max_requests, Some(max) if served >= max){
+            warn!("connection from {} reached the limit of {} requests", peer, served);
+            break;
+        }
+
+        // pipelined requests are answered in one write once the buffered input is drained
+        if reader.buffer().is_empty(){
+            writer.flush()?;
+        }
     }
 
-    fn handle_with_engine<E: KvsEngine>(&self, engine: &mut E){
-        let listener = TcpListener::bind(self.addr)
-                        .unwrap_or_else(|e| panic!("bind server failed: {}", e));
+    writer.flush()?;
+    Ok(())
+}
+
+/// run a command of a connection on a worker of the pool and wait for its response.
+/// the session of the connection goes along with the command and comes back with the response
+fn run_command<E: KvsEngine, P: ThreadPool>(pool: &P, session: Session<E>, op: Command) -> kvs::Result<(Session<E>, Response)>{
+    let (done, finished) = mpsc::channel();
+    pool.spawn(move || {
+        let mut session = session;
+        let response = do_command(&session.engine, &mut session.txn, op);
+        let _ = done.send((session, response));
+    });
+    finished.recv().map_err(|_| KvsError::Io(io::Error::other("the command panicked")))
+}
 
-        for stream in listener.incoming(){
-            let mut stream = stream.unwrap();
-            let mut buffer = [0; 512];
+fn do_command<E: KvsEngine>(engine: &E, txn: &mut Option<Transaction<E>>, op: Command) -> Response{
+    match op{
+        Command::Begin => {
+            if txn.is_some(){
+                return Response::Error(ErrorCode::InvalidCommand.into());
+            }
+            *txn = Some(engine.begin());
+            Response::Null
+        },
 
-            let len = stream.read(&mut buffer).unwrap();
-            let command: Result<Command, _> = serde_json::from_slice(&buffer[0..len]);
+        Command::Commit => {
+            match txn.take().map(Transaction::commit){
+                None => Response::Error(ErrorCode::InvalidCommand.into()),
+                Some(Ok(_)) => Response::Null,
+                Some(Err(e)) => error_response("commit", e),
+            }
+        },
 
-            let response;
-            match command{
-                Ok(op) => {
-                    response = self.do_command(engine, op);
+        Command::Abort => {
+            match txn.take(){
+                Some(txn) => {
+                    txn.abort();
+                    Response::Null
                 },
-                Err(_) => {
-                    response = Response::Error(ServerError::InvalidCommand);
-                }
+                None => Response::Error(ErrorCode::InvalidCommand.into()),
             }
+        },
 
-            let res = serde_json::to_string(&response).unwrap();
-            stream.write(&res.into_bytes()).unwrap();
-            stream.flush().unwrap();
+        op => match txn{
+            Some(txn) => do_in_transaction(engine, txn, op),
+            None => do_on_engine(engine, op),
         }
     }
+}
 
+/// run a command inside the open transaction of a connection
+fn do_in_transaction<E: KvsEngine>(engine: &E, txn: &mut Transaction<E>, op: Command) -> Response{
+    match op{
+        Command::Set(k, v) => {
+            txn.set(k, v);
+            Response::Null
+        },
 
-    fn do_command<E: KvsEngine>(&self, engine: &mut E, op: Command) -> Response{
-       match op{
-           Command::Set(k, v) => {
-               match engine.set(k, v){
-                   Err(_) => {
-                       Response::Error(ServerError::OtherError)
-                   },
-                   Ok(_) => {
-                       Response::Null
-                   }
-               }
-           },
+        Command::Get(k) => {
+            match txn.get(k){
+                Ok(Some(s)) => Response::Value(s),
+                Ok(None) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("get", e),
+            }
+        },
 
-           Command::Get(k) => {
-               match engine.get(k){
-                    Ok(v) => {
-                        match v{
-                            Some(s) => Response::Value(s),
-                            None => Response::Error(ServerError::NotFound)
-                        }
-                    }
-                    Err(_) => Response::Error(ServerError::OtherError),
-               }
-           },
-
-           Command::Rm(k) => {
-                match engine.remove(k){
-                    Ok(_) => Response::Null,
-                    Err(e) => {
-                        match e{
-                            KvsError::NotFound(_) => Response::Error(ServerError::NotFound),
-                            _ => Response::Error(ServerError::OtherError),
-                        }
-                    }
+        Command::Rm(k) => {
+            match txn.remove(k){
+                Ok(_) => Response::Null,
+                Err(KvsError::NotFound(_)) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("remove", e),
+            }
+        },
+
+        Command::Batch(batch) => {
+            for op in batch.into_ops(){
+                match op{
+                    BatchOp::Set(k, v) => txn.set(k, v),
+                    // a batch ignores removes of missing keys
+                    BatchOp::Remove(k) => match txn.remove(k){
+                        Ok(_) | Err(KvsError::NotFound(_)) => {},
+                        Err(e) => return error_response("batch", e),
+                    },
+                }
+            }
+            Response::Null
+        },
+
+        Command::Cas(k, expected, new) => bool_response(txn.compare_and_swap(k, expected, new)),
+
+        Command::SetIfAbsent(k, v) => bool_response(txn.compare_and_swap(k, None, Some(v))),
+
+        Command::SetIfPresent(k, v) => {
+            bool_response(txn.get(k.clone()).map(|current| {
+                let present = current.is_some();
+                if present{
+                    txn.set(k, v);
                 }
-           }
-       }
-   }
+                present
+            }))
+        },
+
+        Command::Exists(k) => {
+            match txn.get(k){
+                Ok(value) => Response::Bool(value.is_some()),
+                Err(e) => error_response("exists", e),
+            }
+        },
+
+        // transactions do not carry expiries, nor track the ranges they scanned, and only work on strings
+        Command::SetWithTtl(..) | Command::Ttl(_) | Command::Persist(_)
+        | Command::Scan(_) | Command::Keys(_) | Command::Count
+        | Command::SetBytes(..) | Command::GetBytes(_) | Command::RmBytes(_)
+        | Command::TtlBytes(_) | Command::PersistBytes(_) | Command::ExistsBytes(_) => {
+            Response::Error(ErrorCode::InvalidCommand.into())
+        },
+
+        Command::Incr(k, delta) => integer_response(txn.incr(k, delta)),
+
+        Command::Decr(k, delta) => integer_response(txn.decr(k, delta)),
+
+        op => do_on_engine(engine, op),
+    }
 }
 
+fn do_on_engine<E: KvsEngine>(engine: &E, op: Command) -> Response{
+    match op{
+        Command::Set(k, v) => {
+            match engine.set(k, v){
+                Ok(_) => Response::Null,
+                Err(e) => error_response("set", e),
+            }
+        },
+
+        Command::Get(k) => {
+            match engine.get(k){
+                Ok(Some(s)) => Response::Value(s),
+                Ok(None) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("get", e),
+            }
+        },
 
+        Command::Rm(k) => {
+            match engine.remove(k){
+                Ok(_) => Response::Null,
+                Err(KvsError::NotFound(_)) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("remove", e),
+            }
+        },
+
+        Command::SetBytes(k, v) => {
+            match engine.set_bytes(k.0, v.0){
+                Ok(_) => Response::Null,
+                Err(e) => error_response("set bytes", e),
+            }
+        },
+
+        Command::GetBytes(k) => {
+            match engine.get_bytes(k.0){
+                Ok(Some(v)) => Response::Bytes(Bytes(v)),
+                Ok(None) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("get bytes", e),
+            }
+        },
+
+        Command::RmBytes(k) => {
+            match engine.remove_bytes(k.0){
+                Ok(_) => Response::Null,
+                Err(KvsError::NotFound(_)) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("remove bytes", e),
+            }
+        },
+
+        Command::SetWithTtl(k, v, ttl) => {
+            match engine.set_with_ttl(k, v, ttl){
+                Ok(_) => Response::Null,
+                Err(e) => error_response("set with ttl", e),
+            }
+        },
+
+        Command::Ttl(k) => {
+            match engine.ttl(k){
+                Ok(ttl) => Response::Ttl(ttl),
+                Err(KvsError::NotFound(_)) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("ttl", e),
+            }
+        },
+
+        Command::Persist(k) => {
+            match engine.persist(k){
+                Ok(had_expiry) => Response::Bool(had_expiry),
+                Err(KvsError::NotFound(_)) => Response::Error(ErrorCode::NotFound.into()),
+                Err(e) => error_response("persist", e),
+            }
+        },
+
+        Command::TtlBytes(k) => {
+            match engine.ttl_bytes(k.0){
+                Ok(ttl) => Response::Ttl(ttl),
+                Err(e) => error_response("ttl", e),
+            }
+        },
+
+        Command::PersistBytes(k) => {
+            match engine.persist_bytes(k.0){
+                Ok(had_expiry) => Response::Bool(had_expiry),
+                Err(e) => error_response("persist", e),
+            }
+        },
+
+        Command::Scan(request) => scan_page(engine, request),
+
+        Command::Keys(request) => keys_page(engine, request),
+
+        Command::Count => {
+            match engine.count(){
+                Ok(count) => Response::Integer(i64::try_from(count).unwrap_or(i64::MAX)),
+                Err(e) => error_response("count", e),
+            }
+        },
+
+        Command::Exists(k) => {
+            match engine.exists(k){
+                Ok(exists) => Response::Bool(exists),
+                Err(e) => error_response("exists", e),
+            }
+        },
+
+        Command::ExistsBytes(k) => {
+            match engine.exists_bytes(k.0){
+                Ok(exists) => Response::Bool(exists),
+                Err(e) => error_response("exists", e),
+            }
+        },
+
+        Command::Compact => {
+            match engine.compact(){
+                Ok(_) => Response::Null,
+                Err(e) => error_response("compaction", e),
+            }
+        },
+
+        Command::Batch(batch) => {
+            match engine.write_batch(batch){
+                Ok(_) => Response::Null,
+                Err(e) => error_response("write batch", e),
+            }
+        },
+
+        Command::Cas(k, expected, new) => bool_response(engine.compare_and_swap(k, expected, new)),
+
+        Command::SetIfAbsent(k, v) => bool_response(engine.set_if_absent(k, v)),
+
+        Command::SetIfPresent(k, v) => bool_response(engine.set_if_present(k, v)),
+
+        Command::Incr(k, delta) => integer_response(engine.incr(k, delta)),
+
+        Command::Decr(k, delta) => integer_response(engine.decr(k, delta)),
+
+        // handled by do_command
+        Command::Begin | Command::Commit | Command::Abort => Response::Error(ErrorCode::InvalidCommand.into()),
+    }
+}
+
+/// one page of a scan. one key more than the page holds is read, to tell where the next page starts
+fn scan_page<E: KvsEngine>(engine: &E, request: ScanRequest) -> Response{
+    let limit = request.limit.clamp(1, MAX_SCAN_PAGE);
+    // keys with the prefix all sort after the prefix itself
+    let start = match (request.start, &request.prefix){
+        (Some(start), Some(prefix)) => Bound::Included(start.max(prefix.clone())),
+        (Some(start), None) => Bound::Included(start),
+        (None, Some(prefix)) => Bound::Included(prefix.clone()),
+        (None, None) => Bound::Unbounded,
+    };
+    let end = request.end.map_or(Bound::Unbounded, Bound::Excluded);
+
+    let mut entries = Vec::with_capacity(limit + 1);
+    for entry in engine.scan((start, end), limit + 1){
+        match entry{
+            Ok((ref key, _)) if request.prefix.as_ref().is_some_and(|prefix| !key.starts_with(prefix)) => break,
+            Ok(entry) => entries.push(entry),
+            Err(e) => return error_response("scan", e),
+        }
+    }
+
+    let next = if entries.len() > limit{
+        entries.pop().map(|(key, _)| key)
+    }else{
+        None
+    };
+    Response::Page(entries, next)
+}
+
+/// one page of the keys matching a pattern. the page ends once it is full or `MAX_KEYS_EXAMINED` keys
+/// were looked at, the key after the last one looked at is where the next page starts
+fn keys_page<E: KvsEngine>(engine: &E, request: KeysRequest) -> Response{
+    let limit = request.limit.clamp(1, MAX_SCAN_PAGE);
+    let pattern = Glob::new(&request.pattern);
+    // matching keys all start with the literal prefix of the pattern, and so sort after it
+    let prefix = pattern.literal_prefix();
+    let start = request.start.map_or_else(|| prefix.clone(), |start| start.max(prefix.clone()));
+
+    let mut keys = Vec::new();
+    for (examined, key) in engine.keys(start.., usize::MAX).enumerate(){
+        let key = match key{
+            Ok(key) => key,
+            Err(e) => return error_response("listing keys", e),
+        };
+        if !key.starts_with(&prefix){
+            break;
+        }
+        if keys.len() == limit || examined == MAX_KEYS_EXAMINED{
+            return Response::Keys(keys, Some(key));
+        }
+        if pattern.matches(&key){
+            keys.push(key);
+        }
+    }
+    Response::Keys(keys, None)
+}
+
+/// the response to a conditional write
+fn bool_response(result: kvs::Result<bool>) -> Response{
+    match result{
+        Ok(done) => Response::Bool(done),
+        Err(e) => error_response("conditional write", e),
+    }
+}
+
+/// the response to an increment or decrement
+fn integer_response(result: kvs::Result<i64>) -> Response{
+    match result{
+        Ok(value) => Response::Integer(value),
+        Err(e) => error_response("increment", e),
+    }
+}
+
+/// the response to a command that failed. errors the client caused are answered as they are,
+/// errors of the store itself are logged here too
+fn error_response(context: &str, e: KvsError) -> Response{
+    let error = ServerError::from(e);
+    match error.code{
+        ErrorCode::Conflict => info!("{} failed: {}", context, error.message),
+        ErrorCode::Corrupted | ErrorCode::StorageFull | ErrorCode::Storage | ErrorCode::Internal => {
+            error!("{} failed: {}", context, error.message)
+        },
+        _ => {},
+    }
+    Response::Error(error)
+}
+
+fn is_timeout(e: &io::Error) -> bool{
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
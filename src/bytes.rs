@@ -0,0 +1,92 @@
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// raw bytes in a request or response, sent in the json frames as a padded base64 string
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl From<Vec<u8>> for Bytes{
+    fn from(bytes: Vec<u8>) -> Self{
+        Bytes(bytes)
+    }
+}
+
+impl Serialize for Bytes{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>{
+        serializer.serialize_str(&encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error>{
+        deserializer.deserialize_str(Base64Visitor)
+    }
+}
+
+struct Base64Visitor;
+
+impl<'de> Visitor<'de> for Base64Visitor{
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        f.write_str("a base64 string")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> std::result::Result<Bytes, E>{
+        decode(text).map(Bytes).map_err(E::custom)
+    }
+}
+
+fn encode(bytes: &[u8]) -> String{
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3){
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        // a chunk of n bytes fills n + 1 digits, padding makes up the rest
+        for i in 0..4{
+            if i <= chunk.len(){
+                text.push(ALPHABET[((group >> (18 - 6 * i)) & 63) as usize] as char);
+            }else{
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn decode(text: &str) -> std::result::Result<Vec<u8>, String>{
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4){
+        return Err(format!("base64 length {} is not a multiple of 4", text.len()));
+    }
+
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    for (n, chunk) in text.chunks(4).enumerate(){
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && (n + 1) * 4 != text.len()){
+            return Err("misplaced base64 padding".to_owned());
+        }
+        let mut group = 0u32;
+        for &c in &chunk[..4 - padding]{
+            let digit = digit(c).ok_or_else(|| format!("invalid base64 character {:?}", c as char))?;
+            group = group << 6 | digit;
+        }
+        group <<= 6 * padding as u32;
+        bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(bytes)
+}
+
+fn digit(c: u8) -> Option<u32>{
+    let digit = match c{
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(digit as u32)
+}
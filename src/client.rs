@@ -0,0 +1,57 @@
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use crate::{Command, Response, KvsError, Result};
+use crate::protocol::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE};
+
+/// a persistent connection to a kvs server.
+/// every request reuses the same tcp stream until the client is dropped
+pub struct KvsClient{
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    max_frame_size: usize,
+}
+
+impl KvsClient{
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self>{
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(KvsClient{
+            reader,
+            writer: BufWriter::new(stream),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        })
+    }
+
+    /// the largest response this client accepts
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize){
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// send one command and wait for its response
+    pub fn request(&mut self, command: &Command) -> Result<Response>{
+        write_frame(&mut self.writer, command)?;
+        self.writer.flush()?;
+        self.read_response()
+    }
+
+    /// send all commands before reading any reply,
+    /// responses are returned in the same order as the commands.
+    ///
+    /// the server only reads ahead as far as its socket buffers allow,
+    /// so very large pipelines should be split into several calls
+    pub fn pipeline(&mut self, commands: &[Command]) -> Result<Vec<Response>>{
+        for command in commands{
+            write_frame(&mut self.writer, command)?;
+        }
+        self.writer.flush()?;
+
+        commands.iter().map(|_| self.read_response()).collect()
+    }
+
+    fn read_response(&mut self) -> Result<Response>{
+        match read_frame(&mut self.reader, self.max_frame_size)?{
+            Some(response) => Ok(response),
+            None => Err(KvsError::ConnectionClosed),
+        }
+    }
+}
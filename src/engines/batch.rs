@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// one operation of a `WriteBatch`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BatchOp{
+    Set(String, String),
+    /// removing a key that does not exist is not an error inside a batch
+    Remove(String),
+}
+
+/// set and remove operations applied together, in order:
+/// either all of them take effect or none does, even across a crash
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch{
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch{
+    pub fn new() -> Self{
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String){
+        self.ops.push(BatchOp::Set(key, value));
+    }
+
+    pub fn remove(&mut self, key: String){
+        self.ops.push(BatchOp::Remove(key));
+    }
+
+    pub fn ops(&self) -> &[BatchOp]{
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp>{
+        self.ops
+    }
+
+    pub fn len(&self) -> usize{
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,43 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+/// when an engine forces acknowledged writes to disk.
+/// anything not forced yet survives the server process dying, but not the machine losing power
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Durability{
+    /// sync before every write is acknowledged
+    Always,
+    /// sync after every N writes
+    EveryN(u64),
+    /// sync at most this long after a write
+    Interval(Duration),
+    /// hand writes to the operating system and let it decide
+    #[default]
+    OsBuffered,
+}
+
+/// parses "always", "every:N", "interval:MILLISECONDS" and "os"
+impl FromStr for Durability{
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String>{
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let arg = parts.next();
+        let invalid = || format!("invalid durability: {}", s);
+
+        match (kind, arg){
+            ("always", None) => Ok(Durability::Always),
+            ("os", None) => Ok(Durability::OsBuffered),
+            ("every", Some(n)) => match n.parse(){
+                Ok(n) if n > 0 => Ok(Durability::EveryN(n)),
+                _ => Err(invalid()),
+            },
+            ("interval", Some(ms)) => match ms.parse(){
+                Ok(ms) if ms > 0 => Ok(Durability::Interval(Duration::from_millis(ms))),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::error;
+use crate::engines::unix_millis;
+use crate::{KvsError, Result};
+use super::reader::KvStoreReader;
+use super::writer::KvStoreWriter;
+use super::hint::{self, Hinted};
+use super::record::{self, LogFormat, FILE_HEADER_LEN};
+use super::{live_entry, log_path, sorted_gen_list, FileOffset, Index, IndexEntry, Op};
+
+/// stale bytes a default `GarbageRatio` policy waits for before compacting
+const DEFAULT_MIN_STALE_BYTES: u64 = 8 * 1024 * 1024;
+
+/// when `KvStore` compacts its logs on its own.
+/// such a compaction merges the logs with the most garbage, `KvStore::compact` merges all of them
+/// regardless of the policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy{
+    /// compact once stale bytes make up more than `ratio` of all log bytes
+    /// and at least `min_stale_bytes` can be reclaimed
+    GarbageRatio{ ratio: f64, min_stale_bytes: u64 },
+    /// compact once more than this many stale bytes piled up
+    StaleBytes(u64),
+    /// compact every interval if there is anything to reclaim
+    Interval(Duration),
+    /// only compact on `KvStore::compact`
+    Manual,
+}
+
+impl Default for CompactionPolicy{
+    fn default() -> Self{
+        CompactionPolicy::GarbageRatio{ ratio: 0.5, min_stale_bytes: DEFAULT_MIN_STALE_BYTES }
+    }
+}
+
+impl CompactionPolicy{
+    /// whether a write leaving the logs with `stats` should start a compaction
+    pub(super) fn should_compact(&self, stats: &GarbageStats) -> bool{
+        let stale = stats.stale_bytes();
+        match *self{
+            CompactionPolicy::GarbageRatio{ ratio, min_stale_bytes } => {
+                stale >= min_stale_bytes && stale as f64 > ratio * (stale + stats.live_bytes) as f64
+            },
+            CompactionPolicy::StaleBytes(max) => stale > max,
+            CompactionPolicy::Interval(_) | CompactionPolicy::Manual => false,
+        }
+    }
+
+    fn interval(&self) -> Option<Duration>{
+        match *self{
+            CompactionPolicy::Interval(interval) => Some(interval),
+            _ => None,
+        }
+    }
+}
+
+/// parses "manual", "ratio:RATIO", "bytes:BYTES" and "interval:SECONDS"
+impl FromStr for CompactionPolicy{
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String>{
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let arg = parts.next();
+        let invalid = || format!("invalid compaction policy: {}", s);
+
+        match (kind, arg){
+            ("manual", None) => Ok(CompactionPolicy::Manual),
+            ("ratio", Some(ratio)) => {
+                let ratio: f64 = ratio.parse().map_err(|_| invalid())?;
+                if !(ratio > 0.0 && ratio < 1.0){
+                    return Err(invalid());
+                }
+                Ok(CompactionPolicy::GarbageRatio{ ratio, min_stale_bytes: DEFAULT_MIN_STALE_BYTES })
+            },
+            ("bytes", Some(bytes)) => bytes.parse().map(CompactionPolicy::StaleBytes).map_err(|_| invalid()),
+            ("interval", Some(secs)) => match secs.parse(){
+                Ok(secs) if secs > 0 => Ok(CompactionPolicy::Interval(Duration::from_secs(secs))),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// live and stale bytes in the logs, stale bytes are tracked per generation
+#[derive(Debug, Default)]
+pub(super) struct GarbageStats{
+    stale: BTreeMap<u64, u64>,
+    /// bytes of the rm records compactions kept for older logs, per generation.
+    /// they are no garbage, but no live data either
+    kept: BTreeMap<u64, u64>,
+    live_bytes: u64,
+}
+
+impl GarbageStats{
+    /// the record at `old` stopped being live without a record of its own:
+    /// it expired, or an rm record kept by a compaction hid it on open
+    pub(super) fn record_dropped(&mut self, old: FileOffset){
+        self.add_stale(old);
+    }
+
+    /// a set record was written at `new`, replacing the record at `old`
+    pub(super) fn record_set(&mut self, new: FileOffset, old: Option<FileOffset>){
+        self.live_bytes += new.len;
+        if let Some(old) = old{
+            self.add_stale(old);
+        }
+    }
+
+    /// an rm record was written at `rm`, removing the record at `old`
+    pub(super) fn record_remove(&mut self, rm: FileOffset, old: Option<FileOffset>){
+        if let Some(old) = old{
+            self.add_stale(old);
+        }
+        *self.stale.entry(rm.gen).or_insert(0) += rm.len;
+    }
+
+    /// a compaction kept the rm record at `rm` for the older logs that may still set its key
+    pub(super) fn record_kept(&mut self, rm: FileOffset){
+        *self.kept.entry(rm.gen).or_insert(0) += rm.len;
+    }
+
+    fn add_stale(&mut self, old: FileOffset){
+        self.live_bytes -= old.len;
+        *self.stale.entry(old.gen).or_insert(0) += old.len;
+    }
+
+    /// a compaction merged the logs of `merged` into `into`, and deleted them.
+    /// records of the merged logs that went stale while it ran went with them,
+    /// copies made stale by a write before the index pointed at them are `garbage` of `into`
+    pub(super) fn merged(&mut self, merged: &BTreeSet<u64>, into: u64, garbage: u64){
+        for gen in merged{
+            self.stale.remove(gen);
+            self.kept.remove(gen);
+        }
+        if garbage > 0{
+            *self.stale.entry(into).or_insert(0) += garbage;
+        }
+    }
+
+    /// forget the garbage of generations whose logs are gone, `gens` being the ones left
+    pub(super) fn retain(&mut self, gens: &[u64]){
+        self.stale.retain(|gen, _| gens.contains(gen));
+        self.kept.retain(|gen, _| gens.contains(gen));
+    }
+
+    /// forget the garbage of generations a compaction is reclaiming
+    pub(super) fn reclaim_below(&mut self, gen: u64){
+        self.stale = self.stale.split_off(&gen);
+    }
+
+    /// forget the garbage of `gens`, see `reclaim_below`
+    pub(super) fn reclaim(&mut self, gens: &[u64]){
+        for gen in gens{
+            self.stale.remove(gen);
+        }
+    }
+
+    /// the generations among `sizes`, pairs of a generation and the length of its log,
+    /// whose logs are at least as stale as all of them together, or hold nothing live at all.
+    /// merging them reclaims the most for the bytes it copies
+    pub(super) fn dirtiest(&self, sizes: &[(u64, u64)]) -> Vec<u64>{
+        let stale = |gen| self.stale.get(&gen).copied().unwrap_or(0);
+        let kept = |gen| self.kept.get(&gen).copied().unwrap_or(0);
+        let total: u64 = sizes.iter().map(|&(_, size)| size).sum();
+        let total_stale: u64 = sizes.iter().map(|&(gen, _)| stale(gen)).sum();
+        sizes.iter()
+            .filter(|&&(gen, size)| {
+                let empty = FILE_HEADER_LEN + stale(gen) + kept(gen) >= size;
+                empty || stale(gen) > 0 && stale(gen) as f64 / size as f64 >= total_stale as f64 / total as f64
+            })
+            .map(|&(gen, _)| gen)
+            .collect()
+    }
+
+    pub(super) fn stale_bytes(&self) -> u64{
+        self.stale.values().sum()
+    }
+}
+
+pub(super) struct CompactionRequest{
+    /// generation the live records of the merged logs are rewritten to
+    pub(super) gen: u64,
+    /// the generations to merge, None for every log below `gen`
+    pub(super) segments: Option<Vec<u64>>,
+    /// told the outcome once the compaction finished
+    pub(super) done: Option<Sender<Result<()>>>,
+}
+
+/// rewrites the live records of old generations on a background thread
+pub(super) struct Compactor{
+    pub(super) path: Arc<PathBuf>,
+    pub(super) index: Arc<Index>,
+    pub(super) reader: KvStoreReader,
+    pub(super) compacting: Arc<AtomicBool>,
+    /// lets an interval policy start compactions while the store is idle
+    pub(super) writer: Weak<Mutex<KvStoreWriter>>,
+}
+
+impl Compactor{
+    pub(super) fn spawn(self, requests: Receiver<CompactionRequest>, policy: CompactionPolicy) -> Result<JoinHandle<()>>{
+        let handle = thread::Builder::new()
+            .name("kvs-compactor".to_owned())
+            .spawn(move || self.run(requests, policy.interval()))?;
+        Ok(handle)
+    }
+
+    fn run(self, requests: Receiver<CompactionRequest>, interval: Option<Duration>){
+        loop{
+            let request = match interval{
+                Some(interval) => match requests.recv_timeout(interval){
+                    Ok(request) => request,
+                    Err(RecvTimeoutError::Timeout) => {
+                        self.compact_on_timer();
+                        continue;
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match requests.recv(){
+                    Ok(request) => request,
+                    Err(_) => break,
+                },
+            };
+
+            let result = self.compaction(request.gen, request.segments);
+            self.compacting.store(false, Ordering::SeqCst);
+            match (request.done, result){
+                (Some(done), result) => {
+                    let _ = done.send(result);
+                },
+                (None, Err(e)) => error!("compaction into generation {} failed: {}", request.gen, e),
+                (None, Ok(())) => {},
+            }
+        }
+    }
+
+    fn compact_on_timer(&self){
+        if let Some(writer) = self.writer.upgrade(){
+            let mut writer = match writer.lock(){
+                Ok(writer) => writer,
+                Err(_) => return,
+            };
+            // the store may be closing, and have stopped taking compactions
+            if writer.compactor.is_some() && writer.has_garbage() && !self.compacting.load(Ordering::SeqCst){
+                if let Err(e) = writer.start_compaction(None, false){
+                    error!("scheduled compaction failed: {}", e);
+                }
+            }
+        }
+    }
+
+    /// copy every live record of the logs of `segments` into `compaction_gen`,
+    /// then point the index at the copies and delete the merged logs.
+    ///
+    /// writers keep appending to the active log meanwhile. a key overwritten after it was copied
+    /// keeps its newer offset, because the index is only updated if it still points to the old record.
+    ///
+    /// the compacted log is replayed after every log below it and before the active log, so its records
+    /// may only be the latest of their keys. that holds for the live records; a key removed or expired
+    /// in a merged log gets an rm record instead, if a log that is not merged and older than that may still set it
+    fn compaction(&self, compaction_gen: u64, segments: Option<Vec<u64>>) -> Result<()>{
+        // an earlier compaction may have merged some of the segments already
+        let sealed: Vec<u64> = sorted_gen_list(&self.path)?.into_iter().filter(|&gen| gen < compaction_gen).collect();
+        let merged: BTreeSet<u64> = match segments{
+            Some(segments) => segments.into_iter().filter(|gen| sealed.contains(gen)).collect(),
+            None => sealed.iter().copied().collect(),
+        };
+        if merged.is_empty(){
+            return Ok(());
+        }
+        let oldest_kept = sealed.iter().copied().find(|gen| !merged.contains(gen));
+
+        // the compacted log only appears under its generation once it is complete,
+        // so a crash halfway leaves nothing but a ".compacting" file behind
+        let compacting_path = self.path.join(format!("{}.compacting", compaction_gen));
+        let compacting_error = |offset| KvsError::file(&compacting_path, Some(offset));
+        let mut compaction_writer = BufWriter::new(File::create(&compacting_path).map_err(KvsError::file(&compacting_path, None))?);
+        compaction_writer.write_all(&record::file_header()).map_err(compacting_error(0))?;
+        let mut moved = Vec::new();
+        let mut offset = FILE_HEADER_LEN;
+        let now = unix_millis();
+
+        for entry in self.index.iter(){
+            let old = entry.value().load();
+            // an expired record is left behind, the expirer takes its key out of the index
+            if !merged.contains(&old.pos.gen) || old.is_expired(now){
+                continue;
+            }
+
+            // never carry a damaged record over into the compacted log
+            let record = self.reader.read_record(old.pos)?;
+            record::decode(&record).map_err(|reason| record::corrupted(old.pos.gen, old.pos.offset, reason))?;
+            compaction_writer.write_all(&record).map_err(compacting_error(offset))?;
+
+            // a move keeps the version and the expiry, it does not change the value
+            let len = record.len() as u64;
+            let new = IndexEntry{ pos: FileOffset{ gen: compaction_gen, offset, len }, ..old };
+            moved.push((entry.key().clone(), old, new));
+            offset += len;
+        }
+
+        let mut removed = Vec::new();
+        if let Some(oldest_kept) = oldest_kept{
+            for key in self.dead_keys(merged.range(oldest_kept + 1..).copied())?{
+                let record = record::encode(&Op::RmRec(key.clone()));
+                compaction_writer.write_all(&record).map_err(compacting_error(offset))?;
+                let len = record.len() as u64;
+                removed.push((key, FileOffset{ gen: compaction_gen, offset, len }));
+                offset += len;
+            }
+        }
+        if offset == FILE_HEADER_LEN{
+            // nothing in the merged logs is needed any more, they go without a compacted log to replace them
+            drop(compaction_writer);
+            fs::remove_file(&compacting_path).map_err(KvsError::file(&compacting_path, None))?;
+        }else{
+            compaction_writer.flush()
+                .and_then(|_| compaction_writer.get_ref().sync_all())
+                .map_err(KvsError::file(&compacting_path, None))?;
+            let compacted = log_path(&self.path, compaction_gen);
+            fs::rename(&compacting_path, &compacted).map_err(KvsError::file(&compacted, None))?;
+            let sets = moved.iter().map(|(key, _, new)| (key.as_slice(), new.pos, Hinted::Set(new.expires_at)));
+            let removes = removed.iter().map(|(key, pos)| (key.as_slice(), *pos, Hinted::Removed));
+            hint::write_hint(&self.path, compaction_gen, sets.chain(removes))?;
+        }
+
+        // a copy of a key overwritten since it was read is garbage from the start
+        let mut garbage = 0;
+        for (key, old, new) in moved{
+            let replaced = match self.index.get(&key){
+                Some(entry) => entry.value().compare_exchange(old, new).is_ok(),
+                None => false,
+            };
+            if !replaced{
+                garbage += new.pos.len;
+            }
+        }
+
+        // readers close their handles to older generations on their next read,
+        // a read already in flight keeps working on its open handle after the file is unlinked
+        self.reader.mark_compacted(merged.iter().copied());
+
+        for &gen in &merged{
+            let log = log_path(&self.path, gen);
+            fs::remove_file(&log).map_err(KvsError::file(&log, None))?;
+            let hint = hint::hint_path(&self.path, gen);
+            match fs::remove_file(&hint){
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+                result => result.map_err(KvsError::file(&hint, None))?,
+            }
+        }
+
+        // the garbage of the merged logs was forgotten when the compaction started,
+        // what went stale in them since is gone with them
+        if let Some(writer) = self.writer.upgrade(){
+            if let Ok(mut writer) = writer.lock(){
+                writer.stats.merged(&merged, compaction_gen, garbage);
+                for (_, pos) in removed{
+                    writer.stats.record_kept(pos);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the keys the records in the logs of `gens` write to that are not live now
+    fn dead_keys(&self, gens: impl Iterator<Item = u64>) -> Result<BTreeSet<Vec<u8>>>{
+        let mut dead = BTreeSet::new();
+        for gen in gens{
+            let log = log_path(&self.path, gen);
+            let mut file = File::open(&log).map_err(KvsError::file(&log, None))?;
+            if record::read_file_header(&mut file, &log)? != LogFormat::Current{
+                continue;
+            }
+            let mut reader = BufReader::new(file);
+            let mut offset = FILE_HEADER_LEN;
+            while let Some(rec) = record::read_next(&mut reader).map_err(KvsError::file(&log, Some(offset)))?{
+                let corrupted = |reason: String| record::corrupted(gen, offset, reason);
+                let ops = if record::is_batch(&rec){
+                    record::decode_batch(&rec).map_err(corrupted)?.into_iter().map(|(op, _, _)| op).collect()
+                }else{
+                    vec![record::decode(&rec).map_err(corrupted)?]
+                };
+                offset += rec.len() as u64;
+
+                for op in ops{
+                    let (Op::SetRec(key, _, _) | Op::RmRec(key)) = op;
+                    if live_entry(&self.index, &key).is_none(){
+                        dead.insert(key);
+                    }
+                }
+            }
+        }
+        Ok(dead)
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::{KvsError, Result};
+use super::writer::KvStoreWriter;
+use super::Op;
+
+/// most writes committed together, so one batch cannot hold the writer for too long
+const MAX_BATCH_LEN: usize = 1024;
+
+/// a write waiting for its batch to be committed
+pub(super) struct PendingWrite{
+    pub(super) op: Op,
+    /// told the outcome once the batch is durable
+    pub(super) done: Sender<Result<()>>,
+}
+
+/// commits the writes of concurrent callers in batches on a background thread,
+/// paying one append and one sync per batch instead of one per write
+pub(super) struct GroupCommitter{
+    pub(super) writer: Weak<Mutex<KvStoreWriter>>,
+    /// how long the first write of a batch waits for more to join it
+    pub(super) max_delay: Duration,
+}
+
+impl GroupCommitter{
+    pub(super) fn spawn(self, writes: Receiver<PendingWrite>) -> Result<JoinHandle<()>>{
+        let handle = thread::Builder::new()
+            .name("kvs-group-commit".to_owned())
+            .spawn(move || self.run(writes))?;
+        Ok(handle)
+    }
+
+    fn run(self, writes: Receiver<PendingWrite>){
+        while let Ok(first) = writes.recv(){
+            let batch = self.collect_batch(first, &writes);
+            let writer = match self.writer.upgrade(){
+                Some(writer) => writer,
+                None => break,
+            };
+
+            let (ops, waiters): (Vec<_>, Vec<_>) = batch.into_iter().map(|write| (write.op, write.done)).unzip();
+            let results = match writer.lock(){
+                Ok(mut writer) => writer.commit_batch(ops),
+                Err(_) => ops.iter().map(|_| Err(KvsError::Poisoned("writer"))).collect(),
+            };
+            for (done, result) in waiters.into_iter().zip(results){
+                // the caller may have given up waiting
+                let _ = done.send(result);
+            }
+        }
+    }
+
+    /// gather the writes arriving within `max_delay` of the first one
+    fn collect_batch(&self, first: PendingWrite, writes: &Receiver<PendingWrite>) -> Vec<PendingWrite>{
+        let deadline = Instant::now() + self.max_delay;
+        let mut batch = vec![first];
+
+        while batch.len() < MAX_BATCH_LEN{
+            let now = Instant::now();
+            let write = if now >= deadline{
+                match writes.try_recv(){
+                    Ok(write) => write,
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+                }
+            }else{
+                match writes.recv_timeout(deadline - now){
+                    Ok(write) => write,
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            };
+            batch.push(write);
+        }
+        batch
+    }
+}
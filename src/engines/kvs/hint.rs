@@ -0,0 +1,156 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use log::warn;
+use crate::{KvsError, Result};
+use super::{log_path, FileOffset};
+
+// A hint file lists where the records of one compacted log are, without their values,
+// so opening the store does not have to read the log. Integers are little endian:
+//
+//   magic "KVSH" | version: u32 | log len: u64 | entries | crc32: u32
+//
+// every entry is `key len: u32 | key | offset: u64 | len: u64 | kind: u8`, followed by the u64 time
+// the key expires at if `kind` is 1. Kind 0 is a set without expiry and kind 2 an rm record, which
+// a compaction keeps if an older log it did not compact may still set the key. The crc covers everything before it,
+// the log len guards against a hint that does not belong to the log next to it.
+
+const MAGIC: [u8; 4] = *b"KVSH";
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = 16;
+const CRC_LEN: usize = 4;
+
+/// what the record of a hint entry does to its key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Hinted{
+    /// sets the key, expiring at the time if there is one
+    Set(Option<u64>),
+    /// removes the key
+    Removed,
+}
+
+/// a key listed by a hint, where its record is and what it does
+pub(super) type HintEntry = (Vec<u8>, FileOffset, Hinted);
+
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf{
+    dir.join(format!("{}.hint", gen))
+}
+
+/// write the hint for the compacted log of `gen`, whose records are `entries`.
+/// it is written aside and renamed into place, so a hint file is always complete
+pub(super) fn write_hint<'a>(dir: &Path, gen: u64, entries: impl Iterator<Item = (&'a [u8], FileOffset, Hinted)>) -> Result<()>{
+    let log = log_path(dir, gen);
+    let log_len = fs::metadata(&log).map_err(KvsError::file(&log, None))?.len();
+
+    let mut hint = Vec::with_capacity(HEADER_LEN);
+    hint.extend_from_slice(&MAGIC);
+    hint.extend_from_slice(&VERSION.to_le_bytes());
+    hint.extend_from_slice(&log_len.to_le_bytes());
+    for (key, pos, hinted) in entries{
+        hint.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        hint.extend_from_slice(key);
+        hint.extend_from_slice(&pos.offset.to_le_bytes());
+        hint.extend_from_slice(&pos.len.to_le_bytes());
+        match hinted{
+            Hinted::Set(Some(at)) => {
+                hint.push(1);
+                hint.extend_from_slice(&at.to_le_bytes());
+            },
+            Hinted::Set(None) => hint.push(0),
+            Hinted::Removed => hint.push(2),
+        }
+    }
+    let crc = crc32fast::hash(&hint);
+    hint.extend_from_slice(&crc.to_le_bytes());
+
+    let compacting_path = dir.join(format!("{}.hint.compacting", gen));
+    let mut writer = BufWriter::new(File::create(&compacting_path).map_err(KvsError::file(&compacting_path, None))?);
+    writer.write_all(&hint)
+        .and_then(|_| writer.flush())
+        .and_then(|_| writer.get_ref().sync_all())
+        .map_err(KvsError::file(&compacting_path, None))?;
+    let path = hint_path(dir, gen);
+    fs::rename(&compacting_path, &path).map_err(KvsError::file(&path, None))?;
+    Ok(())
+}
+
+/// the records listed by the hint of `gen`.
+/// `Ok(None)` if there is no usable hint and the log has to be replayed instead
+pub(super) fn read_hint(dir: &Path, gen: u64) -> Result<Option<Vec<HintEntry>>>{
+    let path = hint_path(dir, gen);
+    let hint = match fs::read(&path){
+        Ok(hint) => hint,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(KvsError::file(&path, None)(e)),
+    };
+    let log = log_path(dir, gen);
+    let log_len = fs::metadata(&log).map_err(KvsError::file(&log, None))?.len();
+
+    match parse_hint(&hint, gen, log_len){
+        Ok(entries) => Ok(Some(entries)),
+        Err(reason) => {
+            warn!("ignoring the hint file of generation {}: {}", gen, reason);
+            Ok(None)
+        }
+    }
+}
+
+fn parse_hint(hint: &[u8], gen: u64, log_len: u64) -> std::result::Result<Vec<HintEntry>, String>{
+    if hint.len() < HEADER_LEN + CRC_LEN || hint[..4] != MAGIC{
+        return Err("not a hint file".to_owned());
+    }
+    let (body, crc) = hint.split_at(hint.len() - CRC_LEN);
+    if read_u32(crc, 0) != crc32fast::hash(body){
+        return Err("checksum mismatch".to_owned());
+    }
+    if read_u32(body, 4) != VERSION{
+        return Err(format!("unsupported version {}", read_u32(body, 4)));
+    }
+    if read_u64(body, 8) != log_len{
+        return Err("it does not match the log".to_owned());
+    }
+
+    let mut entries = Vec::new();
+    let mut at = HEADER_LEN;
+    while at < body.len(){
+        if at + 4 > body.len(){
+            return Err("truncated entry".to_owned());
+        }
+        let key_len = read_u32(body, at) as usize;
+        at += 4;
+        if at + key_len + 17 > body.len(){
+            return Err("truncated entry".to_owned());
+        }
+        let key = body[at..at + key_len].to_vec();
+        at += key_len;
+        let pos = FileOffset{ gen, offset: read_u64(body, at), len: read_u64(body, at + 8) };
+        at += 17;
+        let hinted = match body[at - 1]{
+            0 => Hinted::Set(None),
+            1 if at + 8 <= body.len() => {
+                at += 8;
+                Hinted::Set(Some(read_u64(body, at - 8)))
+            },
+            1 => return Err("truncated entry".to_owned()),
+            2 => Hinted::Removed,
+            kind => return Err(format!("invalid entry kind {}", kind)),
+        };
+        if pos.offset + pos.len > log_len{
+            return Err("an entry points past the end of the log".to_owned());
+        }
+        entries.push((key, pos, hinted));
+    }
+    Ok(entries)
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32{
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64{
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,53 @@
+use std::fs::{File, OpenOptions};
+use fs2::FileExt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use crate::{KvsError, Result};
+
+/// the lock file in the data directory
+const LOCK_FILE: &str = "LOCK";
+
+/// an exclusive lock on a data directory, so no two stores append to the same logs.
+/// it is an advisory lock of the operating system, released when the lock is dropped
+/// or the process exits, however it exits; the lock file keeps the pid of the last holder
+pub(super) struct DirLock{
+    _file: File,
+}
+
+impl DirLock{
+    /// lock `dir`, failing with `KvsError::Locked` if another store holds it
+    pub(super) fn acquire(dir: &Path) -> Result<DirLock>{
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .map_err(KvsError::file(&path, None))?;
+        match FileExt::try_lock_exclusive(&file){
+            Ok(()) => {},
+            Err(ref e) if is_contended(e) => {
+                let holder = read_pid(&mut file).map_err(KvsError::file(&path, None))?;
+                return Err(KvsError::Locked(dir.to_path_buf(), holder));
+            },
+            Err(e) => return Err(KvsError::file(&path, None)(e)),
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(process::id().to_string().as_bytes()))
+            .and_then(|_| file.sync_data())
+            .map_err(KvsError::file(&path, None))?;
+        Ok(DirLock{ _file: file })
+    }
+}
+
+/// whether taking the lock failed because another holds it: `WouldBlock` on unix, a lock violation on windows
+fn is_contended(e: &io::Error) -> bool{
+    e.kind() == io::ErrorKind::WouldBlock || e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+/// the pid in the lock file, None if the holder did not write it yet
+fn read_pid(file: &mut File) -> io::Result<Option<u32>>{
+    let mut pid = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut pid)?;
+    Ok(std::str::from_utf8(&pid).ok().and_then(|pid| pid.trim().parse().ok()))
+}
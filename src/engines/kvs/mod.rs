@@ -0,0 +1,778 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, ErrorKind, SeekFrom};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+use log::warn;
+use crate::{Durability, KvsError, Result};
+use super::{expiry_after, time_left, unix_millis, update_value, IntegerOp, KeyBytesIter, KvsEngine, ScanBytesIter, WriteBatch};
+
+mod compaction;
+mod group_commit;
+mod hint;
+mod lock;
+mod reader;
+mod record;
+mod writer;
+
+pub use self::compaction::CompactionPolicy;
+use self::compaction::{Compactor, GarbageStats};
+use self::group_commit::{GroupCommitter, PendingWrite};
+use self::hint::Hinted;
+use self::lock::DirLock;
+use self::reader::KvStoreReader;
+use self::record::{LogFormat, FILE_HEADER_LEN};
+use self::writer::{spawn_expirer, spawn_syncer, Closer, KvStoreWriter};
+
+/// the size the active log grows to before a new one is started, unless the builder sets another
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// key : IndexEntry.
+/// an overwrite swaps the entry in place rather than re-inserting the key,
+/// since a re-insert would briefly hide the key from concurrent readers
+type Index = SkipMap<Vec<u8>, AtomicCell<IndexEntry>>;
+
+/// where the current record of a key lives, and the version its write got.
+/// versions only live as long as the process: every key loaded on open has version 0,
+/// every write after that gets the next number of the store's write sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexEntry{
+    pos: FileOffset,
+    version: u64,
+    /// when the key expires, in milliseconds since the unix epoch
+    expires_at: Option<u64>,
+}
+
+impl IndexEntry{
+    /// whether the key has expired at `now`, in milliseconds since the unix epoch
+    fn is_expired(&self, now: u64) -> bool{
+        matches!(self.expires_at, Some(at) if at <= now)
+    }
+}
+
+/// a log-structured store.
+/// every clone shares the same index and writer, but reads through its own file handles,
+/// so clones can be handed to different threads
+#[derive(Clone)]
+pub struct KvStore {
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    /// None if the store was opened read-only
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    /// hands writes to the group committer, if group commit is on
+    committer: Option<mpsc::Sender<PendingWrite>>,
+    /// the version of the latest write, the writer bumps it before applying a write
+    seq: Arc<AtomicU64>,
+    /// closes the store when the last clone is dropped, None if it was opened read-only.
+    /// declared last, so the last clone drops its sender to the group committer before closing
+    _closer: Option<Arc<Closer>>,
+}
+
+/// what a transaction saw when it read a key from a `KvStore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyVersion{
+    /// version of the key, None if it did not exist
+    version: Option<u64>,
+    /// the store's write sequence just before the read
+    seq: u64,
+}
+
+/// where a record lives: generation of the log file, offset and length of the record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileOffset{
+    gen: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// an operation of the log
+pub enum Op {
+    /// key, value and the time the key expires at, in milliseconds since the unix epoch
+    SetRec(Vec<u8>, Vec<u8>, Option<u64>),
+    RmRec(Vec<u8>),
+}
+
+/// an operation of the newline-delimited json logs written by earlier versions
+#[derive(Serialize, Deserialize)]
+enum LegacyOp {
+    SetRec(String, String),
+    RmRec(String),
+}
+
+impl From<LegacyOp> for Op {
+    fn from(op: LegacyOp) -> Op {
+        match op {
+            LegacyOp::SetRec(k, v) => Op::SetRec(k.into_bytes(), v.into_bytes(), None),
+            LegacyOp::RmRec(k) => Op::RmRec(k.into_bytes()),
+        }
+    }
+}
+
+impl KvsEngine for KvStore{
+    type ReadVersion = KeyVersion;
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(&key)?.map(|(value, _)| value))
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match &self.committer{
+            Some(committer) => group_commit(committer, Op::SetRec(key, value, None)),
+            None => self.writer()?.set(key, value, None),
+        }
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let (key, value, expires_at) = (key.into_bytes(), value.into_bytes(), Some(expiry_after(ttl)));
+        match &self.committer{
+            Some(committer) => group_commit(committer, Op::SetRec(key, value, expires_at)),
+            None => self.writer()?.set(key, value, expires_at),
+        }
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.lookup(&key)?{
+            Some((_, entry)) => Ok(entry.expires_at.map(time_left)),
+            None => Err(KvsError::NotFound("Key not found".to_owned())),
+        }
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        let mut writer = self.writer()?;
+        match self.lookup(&key)?{
+            Some((value, IndexEntry{ expires_at: Some(_), .. })) => {
+                writer.set(key, value, None)?;
+                Ok(true)
+            },
+            Some(_) => Ok(false),
+            None => Err(KvsError::NotFound("Key not found".to_owned())),
+        }
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        match &self.committer{
+            Some(committer) => group_commit(committer, Op::RmRec(key)),
+            None => self.writer()?.remove(key),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytesIter<'_> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let entries = self.index.range(range)
+            .filter_map(move |entry| {
+                // skips keys removed or expired since the index was walked past them
+                let found = self.lookup(entry.key()).transpose()?;
+                Some(found.map(|(value, _)| (entry.key().clone(), value)))
+            })
+            .take(limit);
+        Box::new(entries)
+    }
+
+    fn exists_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        Ok(live_entry(&self.index, &key).is_some())
+    }
+
+    fn keys_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> KeyBytesIter<'_> {
+        let now = unix_millis();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let keys = self.index.range(range)
+            .filter(move |entry| !entry.value().load().is_expired(now))
+            .map(|entry| Ok(entry.key().clone()))
+            .take(limit);
+        Box::new(keys)
+    }
+
+    fn count(&self) -> Result<u64> {
+        let now = unix_millis();
+        Ok(self.index.iter().filter(|entry| !entry.value().load().is_expired(now)).count() as u64)
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.writer()?.write_batch(batch)
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        // every write takes the writer lock, so the value cannot change until it is released
+        let mut writer = self.writer()?;
+        let current = self.lookup(key.as_bytes())?.map(|(value, _)| value);
+        if current.as_deref() != expected.as_ref().map(String::as_bytes){
+            return Ok(false);
+        }
+        match new{
+            Some(value) => writer.set(key.into_bytes(), value.into_bytes(), None)?,
+            None if current.is_some() => writer.remove(key.into_bytes())?,
+            None => {},
+        }
+        Ok(true)
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        let mut writer = self.writer()?;
+        if live_entry(&self.index, key.as_bytes()).is_none(){
+            return Ok(false);
+        }
+        writer.set(key.into_bytes(), value.into_bytes(), None)?;
+        Ok(true)
+    }
+
+    fn update_integer(&self, key: String, op: IntegerOp) -> Result<i64> {
+        let mut writer = self.writer()?;
+        let current = self.lookup(key.as_bytes())?;
+        let value = update_value(&key, current.as_ref().map(|(value, _)| value.as_slice()), op)?;
+        let expires_at = current.and_then(|(_, entry)| entry.expires_at);
+        writer.set(key.into_bytes(), value.to_string().into_bytes(), expires_at)?;
+        Ok(value)
+    }
+
+    fn read_for_transaction(&self, key: &str) -> Result<(Option<String>, KeyVersion)> {
+        let seq = self.seq.load(Ordering::SeqCst);
+        let found = self.lookup(key.as_bytes())?;
+        let version = found.as_ref().map(|(_, entry)| entry.version);
+        let value = found.map(|(value, _)| String::from_utf8(value)).transpose()?;
+        Ok((value, KeyVersion{ version, seq }))
+    }
+
+    fn commit_transaction(&self, reads: Vec<(String, KeyVersion)>, writes: WriteBatch) -> Result<()> {
+        // nothing changes a read-only store, so what a transaction read there is still current
+        if self.writer.is_none() && writes.is_empty(){
+            return Ok(());
+        }
+        self.writer()?.commit_transaction(reads, writes)
+    }
+}
+
+impl KvStore {
+    /// open the store in `path` with the default options,
+    /// creating the directory if it does not exist
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStoreBuilder::new(path).open()
+    }
+
+    /// open the store in `path` for reading only, see `KvStoreBuilder::open_read_only`
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStoreBuilder::new(path).open_read_only()
+    }
+
+    pub fn builder(path: impl Into<PathBuf>) -> KvStoreBuilder {
+        KvStoreBuilder::new(path)
+    }
+
+    /// the writer, failing with `KvsError::ReadOnly` if the store was opened read-only
+    /// and with `KvsError::Poisoned` once a panic interrupted a write
+    fn writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        match &self.writer{
+            Some(writer) => writer.lock().map_err(|_| KvsError::Poisoned("writer")),
+            None => Err(KvsError::ReadOnly("write".to_owned())),
+        }
+    }
+
+    /// the value of `key` and its index entry, None if it does not exist or expired
+    fn lookup(&self, key: &[u8]) -> Result<Option<(Vec<u8>, IndexEntry)>> {
+        loop{
+            let entry = match live_entry(&self.index, key){
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            match self.reader.read_value(entry.pos){
+                // compaction moved the record and deleted its file after we looked it up,
+                // the index already points to the new location
+                Err(ref e) if e.is_io(ErrorKind::NotFound) && self.reader.is_compacted(entry.pos) => continue,
+                result => return result.map(|value| Some((value, entry))),
+            }
+        }
+    }
+
+    /// rewrite all the logs without their stale records now, whatever the compaction policy.
+    /// returns once the compaction finished
+    pub fn compact(&self) -> Result<()> {
+        let (done, finished) = mpsc::channel();
+        self.writer()?.start_compaction(Some(done), true)?;
+        finished.recv()
+            .map_err(|_| KvsError::Compaction("the compaction thread is gone".to_owned()))?
+    }
+}
+
+/// hand `op` to the group committer and wait until its batch is durable
+fn group_commit(committer: &mpsc::Sender<PendingWrite>, op: Op) -> Result<()> {
+    let gone = || KvsError::Io(io::Error::other("the group commit thread is gone"));
+    let (done, committed) = mpsc::channel();
+    committer.send(PendingWrite{ op, done }).map_err(|_| gone())?;
+    committed.recv().map_err(|_| gone())?
+}
+
+/// options for opening a `KvStore`
+pub struct KvStoreBuilder {
+    path: PathBuf,
+    compaction_policy: CompactionPolicy,
+    quarantine_torn_tail: bool,
+    durability: Durability,
+    group_commit: Option<Duration>,
+    max_segment_size: u64,
+}
+
+impl KvStoreBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        KvStoreBuilder {
+            path: path.into(),
+            compaction_policy: CompactionPolicy::default(),
+            quarantine_torn_tail: true,
+            durability: Durability::default(),
+            group_commit: None,
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+        }
+    }
+
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// when writes are forced to disk, `Durability::OsBuffered` by default
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// commit the writes of concurrent callers together, with one append and one sync per batch.
+    /// a batch waits up to `max_delay` for writes to join it, every caller returns once its batch is durable
+    pub fn group_commit(mut self, max_delay: Duration) -> Self {
+        self.group_commit = Some(max_delay);
+        self
+    }
+
+    /// how long the active log grows before it is sealed and writes move on to a new one, 64 MiB by default.
+    /// background compactions merge only the sealed logs with the most garbage
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// whether a torn record cut off the end of the active log is kept in a "{gen}-{offset}.torn" file,
+    /// on by default
+    pub fn quarantine_torn_tail(mut self, quarantine: bool) -> Self {
+        self.quarantine_torn_tail = quarantine;
+        self
+    }
+
+    /// open the store, creating the directory if it does not exist.
+    /// new records are appended to the newest log file.
+    /// fails with `KvsError::Locked` while another store has the directory open, until that store is dropped.
+    ///
+    /// a record the newest log ends in the middle of was torn by a crash while it was written,
+    /// as was a damaged record no whole record follows; it is cut off so the store opens with every record written before it
+    pub fn open(self) -> Result<KvStore> {
+        let path = Arc::new(self.path);
+        check_dir(&path)?;
+        fs::create_dir_all(&*path).map_err(KvsError::file(&*path, None))?;
+        let lock = DirLock::acquire(&path)?;
+        remove_unfinished_files(&path)?;
+
+        let torn_tail = TornTail::Truncate{ quarantine: self.quarantine_torn_tail };
+        let (index, stats, gen_list) = load_index(&path, torn_tail)?;
+        let index = Arc::new(index);
+        let expiries = index.iter()
+            .filter_map(|entry| entry.value().load().expires_at.map(|at| (at, entry.key().clone())))
+            .collect();
+
+        let gen = gen_list.last().copied().unwrap_or(0);
+        let mut append_file = new_log_file(&path, gen)?;
+        let offset = append_file.seek(SeekFrom::End(0)).map_err(KvsError::file(log_path(&path, gen), None))?;
+
+        let reader = KvStoreReader::new(Arc::clone(&path));
+        let compacting = Arc::new(AtomicBool::new(false));
+        let (compactor, requests) = mpsc::channel();
+
+        let seq = Arc::new(AtomicU64::new(0));
+        let writer = Arc::new(Mutex::new(KvStoreWriter{
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            writter: append_file,
+            gen,
+            offset,
+            max_segment_size: self.max_segment_size,
+            stats,
+            policy: self.compaction_policy,
+            compactor: Some(compactor),
+            compacting: Arc::clone(&compacting),
+            durability: self.durability,
+            unsynced: 0,
+            seq: Arc::clone(&seq),
+            tombstones: HashMap::new(),
+            tombstones_since: 0,
+            expiries,
+        }));
+
+        let compactor_handle = Compactor{
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            reader: reader.clone(),
+            compacting,
+            writer: Arc::downgrade(&writer),
+        }.spawn(requests, self.compaction_policy)?;
+        let mut tickers = Vec::new();
+        if let Durability::Interval(interval) = self.durability{
+            let (stop, stopped) = mpsc::channel();
+            tickers.push((stop, spawn_syncer(Arc::downgrade(&writer), interval, stopped)?));
+        }
+        let (stop, stopped) = mpsc::channel();
+        tickers.push((stop, spawn_expirer(Arc::downgrade(&writer), stopped)?));
+
+        let (committer, committer_handle) = match self.group_commit{
+            Some(max_delay) => {
+                let (committer, writes) = mpsc::channel();
+                let handle = GroupCommitter{ writer: Arc::downgrade(&writer), max_delay }.spawn(writes)?;
+                (Some(committer), Some(handle))
+            },
+            None => (None, None),
+        };
+        let closer = Closer{
+            writer: Arc::clone(&writer),
+            compactor: Some(compactor_handle),
+            committer: committer_handle,
+            tickers,
+            _lock: lock,
+        };
+
+        Ok(KvStore{
+            index,
+            reader,
+            writer: Some(writer),
+            committer,
+            seq,
+            _closer: Some(Arc::new(closer)),
+        })
+    }
+
+    /// open the store as the directory holds it, for reading only.
+    /// nothing in the directory is created, changed or locked, so another process may have the store open
+    /// for writing meanwhile; this store keeps seeing the logs as they were on open, and reads fail once
+    /// a compaction there deleted the logs they need.
+    ///
+    /// every write fails with `KvsError::ReadOnly`, as does opening logs that need an upgrade.
+    /// a record torn at the end of the newest log is left out, it may be one the writer is in the middle of
+    pub fn open_read_only(self) -> Result<KvStore> {
+        let path = Arc::new(self.path);
+        check_dir(&path)?;
+        if !path.exists(){
+            return Err(KvsError::InvalidPath(path.to_path_buf(), "it does not exist".to_owned()));
+        }
+        for gen in sorted_gen_list(&path)?{
+            let log = log_path(&path, gen);
+            let mut file = File::open(&log).map_err(KvsError::file(&log, None))?;
+            if record::read_file_header(&mut file, &log)? == LogFormat::Legacy{
+                return Err(KvsError::ReadOnly(format!("upgrade the json log {}.log", gen)));
+            }
+        }
+        let (index, _, _) = load_index(&path, TornTail::Ignore)?;
+
+        Ok(KvStore{
+            index: Arc::new(index),
+            reader: KvStoreReader::new(path),
+            writer: None,
+            committer: None,
+            seq: Arc::new(AtomicU64::new(0)),
+            _closer: None,
+        })
+    }
+}
+
+/// fail with `KvsError::InvalidPath` if `path` is there but is no directory
+fn check_dir(path: &Path) -> Result<()>{
+    if path.exists() && !path.is_dir(){
+        return Err(KvsError::InvalidPath(path.to_path_buf(), "it is not a directory".to_owned()));
+    }
+    Ok(())
+}
+
+/// replay the logs in `path` into a new index, from their hints where there are some.
+/// `torn_tail` says what to do with a record torn at the end of the newest log.
+/// returns the index, the garbage in the logs and their generations
+fn load_index(path: &Path, torn_tail: TornTail) -> Result<(Index, GarbageStats, Vec<u64>)>{
+    let index = SkipMap::new();
+    let gen_list = sorted_gen_list(path)?;
+    let mut stats = GarbageStats::default();
+    let now = unix_millis();
+    for &gen in &gen_list{
+        if Some(&gen) == gen_list.last(){
+            load_file_to_kvs(path, gen, &index, &mut stats, torn_tail, now)?;
+        }else if !load_hint_to_kvs(path, gen, &index, &mut stats, now)?{
+            load_file_to_kvs(path, gen, &index, &mut stats, TornTail::Refuse, now)?;
+        }
+    }
+    Ok((index, stats, gen_list))
+}
+
+/// rebuild the index entries of a compacted log from its hint file, without reading the log.
+/// returns false if the log has no usable hint
+fn load_hint_to_kvs(path: &Path, gen: u64, index: &Index, stats: &mut GarbageStats, now: u64) -> Result<bool>{
+    let entries = match hint::read_hint(path, gen)?{
+        Some(entries) => entries,
+        None => return Ok(false),
+    };
+    for (key, file_offset, hinted) in entries{
+        match hinted{
+            Hinted::Set(expires_at) => replay_set(&key, file_offset, expires_at, index, stats, now),
+            // kept for the older logs that may set the key, it is no garbage of its own
+            Hinted::Removed => {
+                if let Some(old) = index.remove(&key){
+                    stats.record_dropped(old.value().load().pos);
+                }
+                stats.record_kept(file_offset);
+            },
+        }
+    }
+    Ok(true)
+}
+
+/// what replaying a log does with a record cut short by the end of the file
+#[derive(Clone, Copy)]
+enum TornTail{
+    /// fail with `KvsError::Corrupted`
+    Refuse,
+    /// cut the log off before the record
+    Truncate{ quarantine: bool },
+    /// stop reading before the record, leaving the log as it is
+    Ignore,
+}
+
+/// replay one log file into the index, accounting live and stale bytes.
+/// a log written by an older version is upgraded to the current format first
+fn load_file_to_kvs(path: &Path, gen: u64, index: &Index, stats: &mut GarbageStats, torn_tail: TornTail, now: u64)-> Result<()>{
+    let log = log_path(path, gen);
+    let mut db_file = File::open(&log).map_err(KvsError::file(&log, None))?;
+    match record::read_file_header(&mut db_file, &log)?{
+        LogFormat::Current => {},
+        LogFormat::Empty => return Ok(()),
+        LogFormat::Torn => return recover_torn_tail(path, gen, 0, torn_tail),
+        LogFormat::Legacy => {
+            drop(db_file);
+            upgrade_legacy_log(path, gen, torn_tail)?;
+            return load_file_to_kvs(path, gen, index, stats, torn_tail, now);
+        }
+    }
+    let mut reader = BufReader::new(db_file);
+    let mut offset = FILE_HEADER_LEN;
+
+    // update index
+    loop{
+        let rec = match record::read_next(&mut reader){
+            Ok(Some(rec)) => rec,
+            Ok(None) => break,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return recover_torn_tail(path, gen, offset, torn_tail);
+            },
+            Err(e) => return Err(KvsError::file(&log, Some(offset))(e)),
+        };
+        let len = rec.len() as u64;
+        let file_offset = FileOffset{ gen, offset, len };
+        offset += len;
+
+        let decoded = if record::is_batch(&rec){
+            record::decode_batch(&rec)
+        }else{
+            record::decode(&rec).map(|op| vec![(op, 0, len)])
+        };
+        let ops = match decoded{
+            Ok(ops) => ops,
+            Err(reason) => return recover_damaged_tail(path, gen, file_offset, torn_tail, reason),
+        };
+        for (op, at, len) in ops{
+            replay_op(op, FileOffset{ gen, offset: file_offset.offset + at, len }, index, stats, now);
+        }
+    }
+
+    Ok(())
+}
+
+/// a record that does not decode was torn by a crash, rather than damaged, if no whole record follows it:
+/// the file may have grown before the data landed, leaving zeros or garbage in place of the record.
+/// it is then cut off like a record cut short, else the log is corrupted
+fn recover_damaged_tail(path: &Path, gen: u64, damaged: FileOffset, torn_tail: TornTail, reason: String) -> Result<()>{
+    if let TornTail::Refuse = torn_tail{
+        return Err(record::corrupted(gen, damaged.offset, reason));
+    }
+
+    // the damaged record itself is not searched, the records inside a torn batch are whole
+    let log = log_path(path, gen);
+    let mut rest = Vec::new();
+    File::open(&log)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(damaged.offset + damaged.len))?;
+            file.read_to_end(&mut rest)
+        })
+        .map_err(KvsError::file(&log, Some(damaged.offset)))?;
+    if record::contains_record(&rest){
+        return Err(record::corrupted(gen, damaged.offset, reason));
+    }
+    recover_torn_tail(path, gen, damaged.offset, torn_tail)
+}
+
+/// cut the log of `gen` off at `offset`, where a torn record starts
+fn recover_torn_tail(path: &Path, gen: u64, offset: u64, torn_tail: TornTail) -> Result<()>{
+    let quarantine = match torn_tail{
+        TornTail::Refuse => return Err(record::corrupted(gen, offset, "truncated record")),
+        TornTail::Truncate{ quarantine } => quarantine,
+        TornTail::Ignore => return Ok(()),
+    };
+
+    let log = log_path(path, gen);
+    let mut file = OpenOptions::new().read(true).write(true).open(&log).map_err(KvsError::file(&log, None))?;
+    let len = file.seek(SeekFrom::End(0)).map_err(KvsError::file(&log, None))?;
+    warn!("{}.log ends in a torn record at offset {}, dropping its last {} bytes", gen, offset, len - offset);
+
+    if quarantine{
+        let mut tail = Vec::new();
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_to_end(&mut tail))
+            .map_err(KvsError::file(&log, Some(offset)))?;
+        let quarantine_path = path.join(format!("{}-{}.torn", gen, offset));
+        fs::write(&quarantine_path, tail).map_err(KvsError::file(&quarantine_path, None))?;
+        warn!("the dropped bytes were saved to {}", quarantine_path.display());
+    }
+
+    file.set_len(offset)
+        .and_then(|_| file.sync_all())
+        .map_err(KvsError::file(&log, Some(offset)))?;
+    Ok(())
+}
+
+/// remove what an upgrade or a compaction interrupted by a crash left behind,
+/// the logs they were replacing are all still there
+fn remove_unfinished_files(path: &Path) -> Result<()>{
+    for entry in fs::read_dir(path).map_err(KvsError::file(path, None))?{
+        let path = entry.map_err(KvsError::file(path, None))?.path();
+        let extension = path.extension().and_then(OsStr::to_str);
+        if path.is_file() && (extension == Some("upgrade") || extension == Some("compacting")){
+            fs::remove_file(&path).map_err(KvsError::file(&path, None))?;
+        }
+    }
+    Ok(())
+}
+
+fn replay_op(op: Op, file_offset: FileOffset, index: &Index, stats: &mut GarbageStats, now: u64){
+    match op{
+        Op::SetRec(k, _, expires_at) => replay_set(&k, file_offset, expires_at, index, stats, now),
+        Op::RmRec(k) => {
+            let old = index.remove(&k).map(|entry| entry.value().load().pos);
+            stats.record_remove(file_offset, old);
+        }
+    }
+}
+
+/// point `key` at the set record at `file_offset`.
+/// a set that expired before `now` counts as a remove, so it still hides older sets of its key
+fn replay_set(key: &[u8], file_offset: FileOffset, expires_at: Option<u64>, index: &Index, stats: &mut GarbageStats, now: u64){
+    let entry = IndexEntry{ pos: file_offset, version: 0, expires_at };
+    if entry.is_expired(now){
+        let old = index.remove(key).map(|entry| entry.value().load().pos);
+        stats.record_remove(file_offset, old);
+    }else{
+        let old = update_index(index, key, entry);
+        stats.record_set(file_offset, old.map(|old| old.pos));
+    }
+}
+
+/// rewrite a newline-delimited json log of an older version in the current format.
+/// the new log replaces the old one under the same generation, so replay order is kept
+fn upgrade_legacy_log(path: &Path, gen: u64, torn_tail: TornTail) -> Result<()>{
+    let log = log_path(path, gen);
+    let mut legacy = BufReader::new(File::open(&log).map_err(KvsError::file(&log, None))?);
+    let upgrade_path = path.join(format!("{}.log.upgrade", gen));
+    let upgrade_error = || KvsError::file(&upgrade_path, None);
+    let mut writer = BufWriter::new(File::create(&upgrade_path).map_err(upgrade_error())?);
+    writer.write_all(&record::file_header()).map_err(upgrade_error())?;
+
+    let mut offset = 0;
+    loop{
+        let mut line = Vec::new();
+        let len = legacy.read_until(b'\n', &mut line).map_err(KvsError::file(&log, Some(offset)))? as u64;
+        if len == 0{
+            break;
+        }
+        let op: LegacyOp = match serde_json::from_slice(&line){
+            Ok(op) => op,
+            // a line without its newline is the last one, it was torn while written
+            Err(_) if line.last() != Some(&b'\n') => {
+                recover_torn_tail(path, gen, offset, torn_tail)?;
+                break;
+            },
+            Err(e) => return Err(record::corrupted(gen, offset, e)),
+        };
+        writer.write_all(&record::encode(&op.into())).map_err(upgrade_error())?;
+        offset += len;
+    }
+    writer.flush()
+        .and_then(|_| writer.get_ref().sync_all())
+        .map_err(upgrade_error())?;
+
+    fs::rename(&upgrade_path, &log).map_err(KvsError::file(&log, None))?;
+    Ok(())
+}
+
+/// point `key` at `entry`, returns the entry it replaced
+fn update_index(index: &Index, key: &[u8], entry: IndexEntry) -> Option<IndexEntry>{
+    match index.get(key){
+        Some(current) => Some(current.value().swap(entry)),
+        None => {
+            index.insert(key.to_vec(), AtomicCell::new(entry));
+            None
+        }
+    }
+}
+
+/// the index entry of `key`, unless the key does not exist or expired
+fn live_entry(index: &Index, key: &[u8]) -> Option<IndexEntry>{
+    index.get(key)
+        .map(|entry| entry.value().load())
+        .filter(|entry| !entry.is_expired(unix_millis()))
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf{
+    dir.join(format!("{}.log", gen))
+}
+
+/// open the log file for `gen` and return a writer appending to it.
+/// a new file starts with the file header, so records start at `FILE_HEADER_LEN`
+fn new_log_file(dir: &Path, gen: u64) -> Result<BufWriter<File>>{
+    let log = log_path(dir, gen);
+    let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&log)
+                    .map_err(KvsError::file(&log, None))?;
+    if file.metadata().map_err(KvsError::file(&log, None))?.len() == 0{
+        file.write_all(&record::file_header()).map_err(KvsError::file(&log, Some(0)))?;
+    }
+    Ok(BufWriter::new(file))
+}
+
+/// get the generations of all files in the dir which end with ".log", in ascending order
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list = Vec::new();
+
+    for entry in fs::read_dir(path).map_err(KvsError::file(path, None))? {
+        let path = entry.map_err(KvsError::file(path, None))?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("log")) {
+            if let Some(gen) = path.file_stem().and_then(OsStr::to_str).and_then(|s| s.parse().ok()) {
+                gen_list.push(gen);
+            }
+        }
+    }
+
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
@@ -0,0 +1,107 @@
+use std::cell::RefCell;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+use crate::{KvsError, Result};
+use super::{log_path, record, FileOffset, Op};
+
+/// the read handles of one `KvStore` clone, keyed by generation.
+/// records are read with positional reads, so reading never needs exclusive access to a file
+/// and never waits for the writer or for other readers
+pub(super) struct KvStoreReader{
+    path: Arc<PathBuf>,
+    /// generations compaction deleted, their handles can be closed
+    compacted: Arc<RwLock<BTreeSet<u64>>>,
+    readers: RefCell<BTreeMap<u64, File>>,
+}
+
+impl Clone for KvStoreReader{
+    fn clone(&self) -> Self{
+        KvStoreReader{
+            path: Arc::clone(&self.path),
+            compacted: Arc::clone(&self.compacted),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl KvStoreReader{
+    pub(super) fn new(path: Arc<PathBuf>) -> Self{
+        KvStoreReader{
+            path,
+            compacted: Arc::new(RwLock::new(BTreeSet::new())),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// mark `gens` as compacted
+    pub(super) fn mark_compacted(&self, gens: impl IntoIterator<Item = u64>){
+        self.compacted.write().unwrap_or_else(PoisonError::into_inner).extend(gens);
+        self.close_stale_handles();
+    }
+
+    /// close the handles of compacted generations.
+    /// a compacted file stays readable through a handle opened before it was deleted,
+    /// so reads already in flight are not affected
+    fn close_stale_handles(&self){
+        // a set of generations cannot be left half updated, a panic elsewhere does not matter to it
+        let compacted = self.compacted.read().unwrap_or_else(PoisonError::into_inner);
+        self.readers.borrow_mut().retain(|gen, _| !compacted.contains(gen));
+    }
+
+    /// read the raw bytes of the record at `pos`
+    pub(super) fn read_record(&self, pos: FileOffset) -> Result<Vec<u8>>{
+        self.close_stale_handles();
+
+        let log = || log_path(&self.path, pos.gen);
+        let mut readers = self.readers.borrow_mut();
+        let file = match readers.entry(pos.gen){
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(File::open(log()).map_err(KvsError::file(log(), None))?),
+        };
+
+        let mut record = vec![0; pos.len as usize];
+        read_exact_at(file, &mut record, pos.offset).map_err(KvsError::file(log(), Some(pos.offset)))?;
+        Ok(record)
+    }
+
+    /// read the record at `pos` and verify its checksum
+    pub(super) fn read_op(&self, pos: FileOffset) -> Result<Op>{
+        record::decode(&self.read_record(pos)?).map_err(|reason| record::corrupted(pos.gen, pos.offset, reason))
+    }
+
+    pub(super) fn read_value(&self, pos: FileOffset) -> Result<Vec<u8>>{
+        match self.read_op(pos)?{
+            Op::SetRec(_, v, _) => Ok(v),
+            Op::RmRec(_) => Err(record::corrupted(pos.gen, pos.offset, "the index points to an rm record")),
+        }
+    }
+
+    /// whether `pos` points into a generation that compaction already deleted
+    pub(super) fn is_compacted(&self, pos: FileOffset) -> bool{
+        self.compacted.read().unwrap_or_else(PoisonError::into_inner).contains(&pos.gen)
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()>{
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()>{
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty(){
+        match file.seek_read(buf, offset)?{
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
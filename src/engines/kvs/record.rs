@@ -0,0 +1,265 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use crate::engines::unix_millis;
+use crate::{Corruption, KvsError, Result};
+use super::Op;
+
+// A log file starts with the magic bytes "KVSL" followed by the format version.
+// Every record after the header is laid out as follows, integers are little endian:
+//
+//   crc32: u32 | len: u32 | timestamp: u64 | kind: u8 | key len: u32 | key | value
+//
+// `len` counts the bytes following it and the crc covers `len` and everything after it.
+// Keys and values are taken as they are, they need not be utf-8.
+// The timestamp is the time the record was written, in milliseconds since the unix epoch.
+// A set record of a key with a ttl has its own kind, and its value starts with the u64 time it expires at,
+// in the same unit.
+//
+// A batch record carries the number of its records in place of the key len,
+// followed by complete set and rm records in place of key and value. Its crc covers all of them,
+// so a batch torn or damaged anywhere is dropped as a whole.
+
+const MAGIC: [u8; 4] = *b"KVSL";
+const VERSION: u32 = 2;
+/// version 1 logs only lack the expiring set kind, they read the same
+const VERSION_WITHOUT_TTL: u32 = 1;
+
+/// length of the magic bytes and version at the start of every log file
+pub(super) const FILE_HEADER_LEN: u64 = 8;
+
+/// length of the crc and len fields preceding the checksummed part of a record
+const RECORD_HEADER_LEN: usize = 8;
+
+/// length of timestamp, kind and key len
+const FIXED_BODY_LEN: usize = 13;
+
+const KIND_SET: u8 = 1;
+const KIND_RM: u8 = 2;
+const KIND_BATCH: u8 = 3;
+const KIND_SET_EXPIRING: u8 = 4;
+
+/// what `read_file_header` found at the start of a log file
+#[derive(Debug, PartialEq)]
+pub(super) enum LogFormat{
+    /// the binary format of this version
+    Current,
+    /// newline-delimited json written by earlier versions
+    Legacy,
+    /// a log file without a header yet
+    Empty,
+    /// the header was only partly written
+    Torn,
+}
+
+pub(super) fn file_header() -> [u8; FILE_HEADER_LEN as usize]{
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&VERSION.to_le_bytes());
+    header
+}
+
+/// read the header of the log `file` opened from `path`
+pub(super) fn read_file_header(file: &mut File, path: &Path) -> Result<LogFormat>{
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    let mut filled = 0;
+    while filled < header.len(){
+        match file.read(&mut header[filled..]).map_err(KvsError::file(path, Some(0)))?{
+            0 => break,
+            n => filled += n,
+        }
+    }
+
+    if filled == 0{
+        return Ok(LogFormat::Empty);
+    }
+    if filled < header.len() && header[..filled] == file_header()[..filled]{
+        return Ok(LogFormat::Torn);
+    }
+    if filled < header.len() || header[..4] != MAGIC{
+        return Ok(LogFormat::Legacy);
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..]);
+    match u32::from_le_bytes(version){
+        VERSION | VERSION_WITHOUT_TTL => Ok(LogFormat::Current),
+        version => Err(KvsError::UnsupportedVersion(path.to_owned(), version)),
+    }
+}
+
+/// serialize `op` as one checksummed record
+pub(super) fn encode(op: &Op) -> Vec<u8>{
+    match op{
+        Op::SetRec(k, v, None) => build(KIND_SET, k.len() as u32, &[k, v]),
+        Op::SetRec(k, v, Some(expires_at)) => {
+            build(KIND_SET_EXPIRING, k.len() as u32, &[k, &expires_at.to_le_bytes(), v])
+        },
+        Op::RmRec(k) => build(KIND_RM, k.len() as u32, &[k]),
+    }
+}
+
+/// serialize `ops` as one batch record.
+/// also returns where each op's record starts inside the batch record, and its length
+pub(super) fn encode_batch(ops: &[Op]) -> (Vec<u8>, Vec<(u64, u64)>){
+    let records: Vec<Vec<u8>> = ops.iter().map(encode).collect();
+
+    let mut positions = Vec::with_capacity(records.len());
+    let mut offset = (RECORD_HEADER_LEN + FIXED_BODY_LEN) as u64;
+    for record in &records{
+        positions.push((offset, record.len() as u64));
+        offset += record.len() as u64;
+    }
+
+    let parts: Vec<&[u8]> = records.iter().map(Vec::as_slice).collect();
+    (build(KIND_BATCH, records.len() as u32, &parts), positions)
+}
+
+/// assemble a record of `kind` whose body ends in `parts`
+fn build(kind: u8, key_len: u32, parts: &[&[u8]]) -> Vec<u8>{
+    let timestamp = unix_millis();
+    let len = FIXED_BODY_LEN + parts.iter().map(|part| part.len()).sum::<usize>();
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + len);
+    record.extend_from_slice(&[0; 4]); // crc, filled in below
+    record.extend_from_slice(&(len as u32).to_le_bytes());
+    record.extend_from_slice(&timestamp.to_le_bytes());
+    record.push(kind);
+    record.extend_from_slice(&key_len.to_le_bytes());
+    for part in parts{
+        record.extend_from_slice(part);
+    }
+
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// whether a record read by `read_next` is a batch record
+pub(super) fn is_batch(record: &[u8]) -> bool{
+    record.len() > RECORD_HEADER_LEN + 8 && record[RECORD_HEADER_LEN + 8] == KIND_BATCH
+}
+
+/// parse a whole set or rm record, verifying its checksum.
+/// the error describes what is wrong with the record, the caller knows where it came from
+pub(super) fn decode(record: &[u8]) -> std::result::Result<Op, String>{
+    verify(record)?;
+
+    let body = &record[RECORD_HEADER_LEN..];
+    let kind = body[8];
+    if kind == KIND_BATCH{
+        return Err("unexpected batch record".to_owned());
+    }
+    let key_len = read_u32(body, 9) as usize;
+    if FIXED_BODY_LEN + key_len > body.len(){
+        return Err("key length exceeds the record".to_owned());
+    }
+    let key = &body[FIXED_BODY_LEN..FIXED_BODY_LEN + key_len];
+    let value = &body[FIXED_BODY_LEN + key_len..];
+
+    let key = key.to_vec();
+    match kind{
+        KIND_SET => Ok(Op::SetRec(key, value.to_vec(), None)),
+        KIND_SET_EXPIRING => {
+            if value.len() < 8{
+                return Err("expiry exceeds the record".to_owned());
+            }
+            let mut expires_at = [0; 8];
+            expires_at.copy_from_slice(&value[..8]);
+            Ok(Op::SetRec(key, value[8..].to_vec(), Some(u64::from_le_bytes(expires_at))))
+        },
+        KIND_RM => Ok(Op::RmRec(key)),
+        kind => Err(format!("unknown record kind {}", kind)),
+    }
+}
+
+/// parse a whole batch record, verifying its checksum.
+/// returns the ops with where their records start inside the batch record, and their lengths
+pub(super) fn decode_batch(record: &[u8]) -> std::result::Result<Vec<(Op, u64, u64)>, String>{
+    verify(record)?;
+    if !is_batch(record){
+        return Err("not a batch record".to_owned());
+    }
+
+    let count = read_u32(record, RECORD_HEADER_LEN + 9) as usize;
+    let mut ops = Vec::with_capacity(count.min(record.len()));
+    let mut at = RECORD_HEADER_LEN + FIXED_BODY_LEN;
+    for _ in 0..count{
+        if at + RECORD_HEADER_LEN > record.len(){
+            return Err("batch ends early".to_owned());
+        }
+        let len = RECORD_HEADER_LEN + read_u32(record, at + 4) as usize;
+        if at + len > record.len(){
+            return Err("batch ends early".to_owned());
+        }
+        let op = decode(&record[at..at + len])?;
+        ops.push((op, at as u64, len as u64));
+        at += len;
+    }
+    if at != record.len(){
+        return Err("trailing bytes after the batch".to_owned());
+    }
+    Ok(ops)
+}
+
+/// check the length and checksum of a whole record
+fn verify(record: &[u8]) -> std::result::Result<(), String>{
+    if record.len() < RECORD_HEADER_LEN + FIXED_BODY_LEN{
+        return Err("record too short".to_owned());
+    }
+    let len = read_u32(record, 4) as usize;
+    if len != record.len() - RECORD_HEADER_LEN{
+        return Err(format!("record length {} does not match its header", record.len()));
+    }
+    if read_u32(record, 0) != crc32fast::hash(&record[4..]){
+        return Err("checksum mismatch".to_owned());
+    }
+    Ok(())
+}
+
+/// whether a whole record with a valid checksum starts anywhere in `bytes`
+pub(super) fn contains_record(bytes: &[u8]) -> bool{
+    (0..bytes.len()).any(|at| {
+        let rest = &bytes[at..];
+        if rest.len() < RECORD_HEADER_LEN + FIXED_BODY_LEN{
+            return false;
+        }
+        let len = read_u32(rest, 4) as usize;
+        len >= FIXED_BODY_LEN && len <= rest.len() - RECORD_HEADER_LEN && verify(&rest[..RECORD_HEADER_LEN + len]).is_ok()
+    })
+}
+
+/// read the raw bytes of the next record, `Ok(None)` at the end of the log
+pub(super) fn read_next<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>>{
+    let mut header = [0; RECORD_HEADER_LEN];
+    let mut filled = 0;
+    while filled < RECORD_HEADER_LEN{
+        match reader.read(&mut header[filled..]){
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // a damaged length must not make us allocate its full size up front
+    let len = read_u32(&header, 4) as u64;
+    let mut record = header.to_vec();
+    reader.take(len).read_to_end(&mut record)?;
+    if record.len() as u64 != RECORD_HEADER_LEN as u64 + len{
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(record))
+}
+
+/// a `Corrupted` error for the record at `offset` of generation `gen`
+pub(super) fn corrupted(gen: u64, offset: u64, reason: impl std::fmt::Display) -> KvsError{
+    KvsError::Corrupted(Corruption{ file: format!("{}.log", gen).into(), offset, reason: reason.to_string() })
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32{
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
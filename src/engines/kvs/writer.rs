@@ -0,0 +1,492 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::slice;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::error;
+use crate::engines::unix_millis;
+use crate::{BatchOp, Durability, KvsError, Result, WriteBatch};
+use super::compaction::{CompactionPolicy, CompactionRequest, GarbageStats};
+use super::lock::DirLock;
+use super::record::{self, FILE_HEADER_LEN};
+use super::{live_entry, log_path, new_log_file, sorted_gen_list, update_index, FileOffset, Index, IndexEntry, KeyVersion, Op};
+
+/// appends records to the newest log file and hands compaction to the background compactor
+pub(super) struct KvStoreWriter{
+    pub(super) path: Arc<PathBuf>,
+    pub(super) index: Arc<Index>,
+    pub(super) writter: BufWriter<File>,
+    pub(super) gen: u64,
+    pub(super) offset: u64,
+    /// the active log is sealed and a new one started once it grew this long
+    pub(super) max_segment_size: u64,
+    pub(super) stats: GarbageStats,
+    pub(super) policy: CompactionPolicy,
+    pub(super) compactor: Option<Sender<CompactionRequest>>,
+    pub(super) compacting: Arc<AtomicBool>,
+    pub(super) durability: Durability,
+    /// records appended to the active log since it was last synced
+    pub(super) unsynced: u64,
+    /// the version of the latest write
+    pub(super) seq: Arc<AtomicU64>,
+    /// the version each key was removed with, for transactions that read the key as missing
+    pub(super) tombstones: HashMap<Vec<u8>, u64>,
+    /// removes older than this are no longer in `tombstones`
+    pub(super) tombstones_since: u64,
+    /// every key with an expiry, by the time it expires at
+    pub(super) expiries: BTreeSet<(u64, Vec<u8>)>,
+}
+
+/// most removes remembered for transaction validation, before they are forgotten at once
+const MAX_TOMBSTONES: usize = 100_000;
+
+/// how often the expirer looks for expired keys
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// most keys expired while holding the writer lock
+const MAX_EXPIRED_PER_ROUND: usize = 10_000;
+
+impl KvStoreWriter{
+    /// set `key`, expiring at `expires_at` milliseconds since the unix epoch
+    pub(super) fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()>{
+        let op = Op::SetRec(key, value, expires_at);
+        let file_offset = self.append(&op)?;
+        self.apply(op, file_offset);
+
+        self.maybe_compact()
+    }
+
+    pub(super) fn remove(&mut self, key: Vec<u8>) -> Result<()>{
+        if live_entry(&self.index, &key).is_none(){
+            return Err(KvsError::NotFound("Key not found".to_owned()));
+        }
+
+        let op = Op::RmRec(key);
+        let file_offset = self.append(&op)?;
+        self.apply(op, file_offset);
+
+        self.maybe_compact()
+    }
+
+    /// write `batch` as a single batch record, so replay applies all of it or nothing.
+    /// removes of keys that do not exist at that point of the batch are dropped
+    pub(super) fn write_batch(&mut self, batch: WriteBatch) -> Result<()>{
+        let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut ops = Vec::with_capacity(batch.len());
+        for op in batch.into_ops(){
+            match op{
+                BatchOp::Set(key, value) => {
+                    let key = key.into_bytes();
+                    exists.insert(key.clone(), true);
+                    ops.push(Op::SetRec(key, value.into_bytes(), None));
+                },
+                BatchOp::Remove(key) => {
+                    let key = key.into_bytes();
+                    let present = exists.get(&key).copied().unwrap_or_else(|| live_entry(&self.index, &key).is_some());
+                    if present{
+                        exists.insert(key.clone(), false);
+                        ops.push(Op::RmRec(key));
+                    }
+                },
+            }
+        }
+        if ops.is_empty(){
+            return Ok(());
+        }
+
+        let (batch_record, positions) = record::encode_batch(&ops);
+        let batch_offset = self.write_record(&batch_record)?;
+        self.finish_append(1, false)?;
+
+        for (op, (at, len)) in ops.into_iter().zip(positions){
+            self.apply(op, FileOffset{ gen: batch_offset.gen, offset: batch_offset.offset + at, len });
+        }
+        self.maybe_compact()
+    }
+
+    /// apply `ops` in order with a single append and sync for all of them.
+    /// every op gets its own result: a remove of a missing key fails on its own,
+    /// a failed append fails every op of the batch
+    pub(super) fn commit_batch(&mut self, ops: Vec<Op>) -> Vec<Result<()>>{
+        let mut rejected = Vec::with_capacity(ops.len());
+        let mut accepted = Vec::with_capacity(ops.len());
+        // whether a key exists once the ops accepted so far are applied
+        let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+
+        for op in ops{
+            match &op{
+                Op::SetRec(key, _, _) => {
+                    exists.insert(key.clone(), true);
+                },
+                Op::RmRec(key) => {
+                    let present = exists.get(key).copied().unwrap_or_else(|| live_entry(&self.index, key).is_some());
+                    if !present{
+                        rejected.push(Some(KvsError::NotFound("Key not found".to_owned())));
+                        continue;
+                    }
+                    exists.insert(key.clone(), false);
+                },
+            }
+            rejected.push(None);
+            accepted.push(op);
+        }
+
+        // the callers are told their writes are durable, so the batch is synced whatever the durability setting
+        let outcome = self.append_all(&accepted, true).and_then(|positions| {
+            for (op, file_offset) in accepted.into_iter().zip(positions){
+                self.apply(op, file_offset);
+            }
+            self.maybe_compact()
+        });
+
+        rejected.into_iter().map(|rejected| match (rejected, &outcome){
+            (Some(e), _) => Err(e),
+            (None, Ok(())) => Ok(()),
+            (None, Err(e)) => Err(KvsError::Io(io::Error::other(format!("group commit failed: {}", e)))),
+        }).collect()
+    }
+
+    /// point the index at a record just written at `file_offset`, under the next version
+    fn apply(&mut self, op: Op, file_offset: FileOffset){
+        // bumped before the index changes, so a transaction reading the key
+        // either sees the old entry or a sequence no older than the new version
+        let version = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        match op{
+            Op::SetRec(key, _, expires_at) => {
+                self.tombstones.remove(&key);
+                let old = update_index(&self.index, &key, IndexEntry{ pos: file_offset, version, expires_at });
+                self.stats.record_set(file_offset, old.map(|old| old.pos));
+                self.unschedule(&key, old);
+                if let Some(at) = expires_at{
+                    self.expiries.insert((at, key));
+                }
+            },
+            Op::RmRec(key) => {
+                let old = self.index.remove(&key).map(|entry| entry.value().load());
+                self.stats.record_remove(file_offset, old.map(|old| old.pos));
+                self.unschedule(&key, old);
+                self.add_tombstone(key, version);
+            },
+        }
+    }
+
+    /// forget the expiry of the entry `key` had before a write
+    fn unschedule(&mut self, key: &[u8], old: Option<IndexEntry>){
+        if let Some(at) = old.and_then(|old| old.expires_at){
+            self.expiries.remove(&(at, key.to_vec()));
+        }
+    }
+
+    fn add_tombstone(&mut self, key: Vec<u8>, version: u64){
+        if self.tombstones.len() >= MAX_TOMBSTONES{
+            self.tombstones.clear();
+            self.tombstones_since = version;
+        }
+        self.tombstones.insert(key, version);
+    }
+
+    /// take up to `limit` keys that expired by `now` out of the index, returns whether more are due.
+    /// their records become garbage, no rm record is needed since replay skips expired sets
+    pub(super) fn expire(&mut self, now: u64, limit: usize) -> bool{
+        for _ in 0..limit{
+            match self.expiries.first(){
+                Some(&(at, _)) if at <= now => {},
+                _ => return false,
+            }
+            let (at, key) = match self.expiries.pop_first(){
+                Some(due) => due,
+                None => return false,
+            };
+
+            let expired = match self.index.get(&key){
+                Some(entry) if entry.value().load().expires_at == Some(at) => {
+                    entry.remove();
+                    Some(entry.value().load().pos)
+                },
+                _ => None,
+            };
+            if let Some(pos) = expired{
+                self.stats.record_dropped(pos);
+                // transactions that read the key before it expired must see it changed
+                let version = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+                self.add_tombstone(key, version);
+            }
+        }
+        matches!(self.expiries.first(), Some(&(at, _)) if at <= now)
+    }
+
+    /// apply `writes` as one batch if no key in `reads` changed since the transaction read it
+    pub(super) fn commit_transaction(&mut self, reads: Vec<(String, KeyVersion)>, writes: WriteBatch) -> Result<()>{
+        for (key, read) in reads{
+            let current = live_entry(&self.index, key.as_bytes()).map(|entry| entry.version);
+            let changed = match (read.version, current){
+                (Some(seen), Some(now)) => seen != now,
+                // missing then and now, but it may have been set and removed in between
+                (None, None) => read.seq < self.tombstones_since
+                    || matches!(self.tombstones.get(key.as_bytes()), Some(&removed) if removed > read.seq),
+                _ => true,
+            };
+            if changed{
+                return Err(KvsError::Conflict(key));
+            }
+        }
+        self.write_batch(writes)
+    }
+
+    /// write one record to the active log and return where it was written
+    fn append(&mut self, op: &Op) -> Result<FileOffset>{
+        Ok(self.append_all(slice::from_ref(op), false)?[0])
+    }
+
+    /// write `ops` to the active log with one flush, syncing as the durability setting asks, or in any case if `sync`.
+    /// returns where each record was written
+    fn append_all(&mut self, ops: &[Op], sync: bool) -> Result<Vec<FileOffset>>{
+        let mut positions = Vec::with_capacity(ops.len());
+        for op in ops{
+            positions.push(self.write_record(&record::encode(op))?);
+        }
+        self.finish_append(ops.len() as u64, sync)?;
+        Ok(positions)
+    }
+
+    /// write `record` to the active log without flushing it
+    fn write_record(&mut self, record: &[u8]) -> Result<FileOffset>{
+        self.writter.write_all(record).map_err(self.io_error(Some(self.offset)))?;
+        let file_offset = FileOffset{
+            gen: self.gen,
+            offset: self.offset,
+            len: record.len() as u64,
+        };
+        self.offset += record.len() as u64;
+        Ok(file_offset)
+    }
+
+    /// flush the `records` just written, and sync them as the durability setting asks, or in any case if `sync`.
+    /// moves on to a new active log if the current one is full, only after the sync
+    fn finish_append(&mut self, records: u64, sync: bool) -> Result<()>{
+        // make sure reader can get value immediately after set
+        self.writter.flush().map_err(self.io_error(None))?;
+
+        self.unsynced += records;
+        match self.durability{
+            _ if sync => self.sync()?,
+            Durability::Always => self.sync()?,
+            Durability::EveryN(n) if self.unsynced >= n => self.sync()?,
+            _ => {},
+        }
+        if self.offset >= self.max_segment_size{
+            self.rotate(1)?;
+        }
+        Ok(())
+    }
+
+    /// seal the active log and continue in the log `gens` generations later.
+    /// a sealed log is never written again, until a compaction merges it away.
+    /// an active log without a single record is deleted instead of sealed.
+    /// the log is synced first whatever the durability setting: only the newest log may end in a torn record
+    fn rotate(&mut self, gens: u64) -> Result<()>{
+        self.sync()?;
+        let sealed = log_path(&self.path, self.gen);
+        let empty = self.offset == FILE_HEADER_LEN;
+        self.gen += gens;
+        self.writter = new_log_file(&self.path, self.gen)?;
+        self.offset = FILE_HEADER_LEN;
+        if empty{
+            fs::remove_file(&sealed).map_err(KvsError::file(&sealed, None))?;
+        }
+        Ok(())
+    }
+
+    /// force the records appended to the active log to disk
+    pub(super) fn sync(&mut self) -> Result<()>{
+        if self.unsynced > 0{
+            self.writter.get_ref().sync_data().map_err(self.io_error(None))?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// turns an io error on the active log into `KvsError::FileIo`
+    fn io_error(&self, offset: Option<u64>) -> impl FnOnce(io::Error) -> KvsError{
+        KvsError::file(log_path(&self.path, self.gen), offset)
+    }
+
+    fn maybe_compact(&mut self) -> Result<()>{
+        if self.policy.should_compact(&self.stats) && !self.compacting.load(Ordering::SeqCst){
+            self.start_compaction(None, false)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn has_garbage(&self) -> bool{
+        self.stats.stale_bytes() > 0
+    }
+
+    /// switch to a fresh active log and let the compactor merge the logs before it:
+    /// all of them if `full`, else the ones with the most garbage, if any.
+    /// the generation between the old and the new active log is reserved for the compacted records
+    pub(super) fn start_compaction(&mut self, done: Option<Sender<Result<()>>>, full: bool) -> Result<()>{
+        let segments = if full{
+            None
+        }else{
+            let mut sizes = Vec::new();
+            for gen in sorted_gen_list(&self.path)?{
+                let size = if gen == self.gen{
+                    self.offset
+                }else{
+                    let log = log_path(&self.path, gen);
+                    fs::metadata(&log).map_err(KvsError::file(&log, None))?.len()
+                };
+                sizes.push((gen, size));
+            }
+            let gens: Vec<u64> = sizes.iter().map(|&(gen, _)| gen).collect();
+            self.stats.retain(&gens);
+            let dirtiest = self.stats.dirtiest(&sizes);
+            if dirtiest.is_empty(){
+                if let Some(done) = done{
+                    let _ = done.send(Ok(()));
+                }
+                return Ok(());
+            }
+            Some(dirtiest)
+        };
+
+        let compaction_gen = self.gen + 1;
+        self.rotate(2)?;
+        // the garbage of the merged logs is about to be reclaimed
+        match &segments{
+            Some(segments) => self.stats.reclaim(segments),
+            None => self.stats.reclaim_below(compaction_gen),
+        }
+
+        let compactor = match &self.compactor{
+            Some(compactor) => compactor,
+            None => return Err(KvsError::Compaction("the compaction thread is gone".to_owned())),
+        };
+        self.compacting.store(true, Ordering::SeqCst);
+        if compactor.send(CompactionRequest{ gen: compaction_gen, segments, done }).is_err(){
+            self.compacting.store(false, Ordering::SeqCst);
+            return Err(KvsError::Compaction("the compaction thread is gone".to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// sync the active log every `interval` until `stop` is closed
+pub(super) fn spawn_syncer(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration, stop: Receiver<()>) -> Result<JoinHandle<()>>{
+    let handle = thread::Builder::new()
+        .name("kvs-syncer".to_owned())
+        .spawn(move || loop{
+            if stopped(&stop, interval){
+                break;
+            }
+            let writer = match writer.upgrade(){
+                Some(writer) => writer,
+                None => break,
+            };
+            let result = match writer.lock(){
+                Ok(mut writer) => writer.sync(),
+                Err(_) => break,
+            };
+            if let Err(e) = result{
+                error!("syncing the log failed: {}", e);
+            }
+        })?;
+    Ok(handle)
+}
+
+/// take expired keys out of the index every `EXPIRE_INTERVAL` until `stop` is closed,
+/// so the index does not keep growing with keys nobody reads
+pub(super) fn spawn_expirer(writer: Weak<Mutex<KvStoreWriter>>, stop: Receiver<()>) -> Result<JoinHandle<()>>{
+    let handle = thread::Builder::new()
+        .name("kvs-expirer".to_owned())
+        .spawn(move || loop{
+            if stopped(&stop, EXPIRE_INTERVAL){
+                break;
+            }
+            let writer = match writer.upgrade(){
+                Some(writer) => writer,
+                None => break,
+            };
+            // the lock is released between rounds, so writers are not held up by many keys expiring at once.
+            // a writer poisoned by a panic is left alone, every write fails on it anyway
+            loop{
+                let more = match writer.lock(){
+                    Ok(mut writer) => writer.expire(unix_millis(), MAX_EXPIRED_PER_ROUND),
+                    Err(_) => return,
+                };
+                if !more{
+                    break;
+                }
+            }
+        })?;
+    Ok(handle)
+}
+
+/// wait `interval` for `stop` to close, true if it did
+fn stopped(stop: &Receiver<()>, interval: Duration) -> bool{
+    match stop.recv_timeout(interval){
+        Ok(()) | Err(RecvTimeoutError::Timeout) => false,
+        Err(RecvTimeoutError::Disconnected) => true,
+    }
+}
+
+/// closes the store once the last of its handles is dropped.
+/// the background threads only hold the writer weakly and are joined here, before the writer and then the
+/// directory lock are dropped, so the next store to open the directory never races a compaction or sync of this one
+pub(super) struct Closer{
+    pub(super) writer: Arc<Mutex<KvStoreWriter>>,
+    pub(super) compactor: Option<JoinHandle<()>>,
+    /// the group committer stops once the last handle dropped its sender
+    pub(super) committer: Option<JoinHandle<()>>,
+    /// the syncer and expirer, each stops once its sender is dropped
+    pub(super) tickers: Vec<(Sender<()>, JoinHandle<()>)>,
+    /// released last, after the writer is gone
+    pub(super) _lock: DirLock,
+}
+
+impl Drop for Closer{
+    fn drop(&mut self){
+        // the writes still waiting for a batch are committed before the last sync
+        if let Some(handle) = self.committer.take(){
+            if handle.join().is_err(){
+                error!("the group commit thread panicked");
+            }
+        }
+        for (stop, handle) in self.tickers.drain(..){
+            drop(stop);
+            if handle.join().is_err(){
+                error!("a background thread of the store panicked");
+            }
+        }
+
+        {
+            // a writer poisoned by a panic is still synced and its compactor still stopped
+            let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+            if writer.policy.should_compact(&writer.stats) && !writer.compacting.load(Ordering::SeqCst){
+                if let Err(e) = writer.start_compaction(None, false){
+                    error!("compaction on close failed: {}", e);
+                }
+            }
+
+            if writer.durability != Durability::OsBuffered{
+                if let Err(e) = writer.sync(){
+                    error!("syncing the log on close failed: {}", e);
+                }
+            }
+
+            // closing the channel stops the compactor once it finished the running compaction
+            writer.compactor.take();
+        }
+        if let Some(handle) = self.compactor.take(){
+            if handle.join().is_err(){
+                error!("the compaction thread panicked");
+            }
+        }
+    }
+}
@@ -1,19 +1,248 @@
-use crate::Result;
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::{Glob, KvsError, Result};
 
-pub trait KvsEngine{
-    /// set key-value pair into database
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+/// a storage engine shared by many threads.
+/// every thread works on its own clone, all clones see the same data
+pub trait KvsEngine: Clone + Send + 'static{
+    /// what a transaction remembers about a key it read, to tell at commit whether it changed
+    type ReadVersion: Clone + Send;
+
+    /// set key-value pair into database.
+    /// like every write but `set_with_ttl` and `incr`, it clears the expiry of the key
+    fn set(&self, key: String, value: String) -> Result<()>{
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// set a key-value pair of arbitrary bytes, see `set`
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// set key-value pair that disappears once `ttl` has passed, also across a restart
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
+    /// the time `key` has left before it expires, None if it does not expire.
+    /// fails with `KvsError::NotFound` if the key does not exist
+    fn ttl(&self, key: String) -> Result<Option<Duration>>{
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    /// `ttl` of a key of arbitrary bytes
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// clear the expiry of `key`, returns whether it had one.
+    /// fails with `KvsError::NotFound` if the key does not exist
+    fn persist(&self, key: String) -> Result<bool>{
+        self.persist_bytes(key.into_bytes())
+    }
+
+    /// `persist` of a key of arbitrary bytes
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool>;
     
     /// get a value by key.
-    /// the result will be None when the key is not exists
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// the result will be None when the key is not exists,
+    /// fails with `KvsError::FromUtf8Error` if the value is not utf-8
+    fn get(&self, key: String) -> Result<Option<String>>{
+        match self.get_bytes(key.into_bytes())?{
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// get a value of arbitrary bytes by a key of arbitrary bytes
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
     /// remove a value from database
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()>{
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// remove the value of a key of arbitrary bytes
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// whether `key` exists, without reading its value
+    fn exists(&self, key: String) -> Result<bool>{
+        self.exists_bytes(key.into_bytes())
+    }
+
+    /// `exists` of a key of arbitrary bytes
+    fn exists_bytes(&self, key: Vec<u8>) -> Result<bool>;
+
+    /// the keys within `range` in ascending order with their values, at most `limit` of them.
+    /// keys written during the scan may or may not show up.
+    /// entries whose key or value is not utf-8 are left out, `scan_bytes` lists them
+    fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> ScanIter<'_>{
+        let entries = self.scan_bytes(byte_range(range), usize::MAX)
+            .filter_map(|entry| match entry{
+                Ok((key, value)) => Some(Ok((String::from_utf8(key).ok()?, String::from_utf8(value).ok()?))),
+                Err(e) => Some(Err(e)),
+            })
+            .take(limit);
+        Box::new(entries)
+    }
+
+    /// the keys of arbitrary bytes within `range` in ascending order with their values, see `scan`
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytesIter<'_>;
+
+    /// the keys starting with `prefix` in ascending order with their values, at most `limit` of them
+    fn scan_prefix(&self, prefix: &str, limit: usize) -> ScanIter<'_>{
+        let prefix = prefix.to_owned();
+        // the keys with the prefix all follow the prefix itself, up to the first key without it
+        let entries = self.scan(prefix.clone().., usize::MAX)
+            .take_while(move |entry| match entry{
+                Ok((key, _)) => key.starts_with(&prefix),
+                Err(_) => true,
+            })
+            .take(limit);
+        Box::new(entries)
+    }
+
+    /// the keys within `range` in ascending order, at most `limit` of them, without reading their values.
+    /// keys that are not utf-8 are left out, `keys_bytes` lists them
+    fn keys<R: RangeBounds<String>>(&self, range: R, limit: usize) -> KeyIter<'_>{
+        let keys = self.keys_bytes(byte_range(range), usize::MAX)
+            .filter_map(|key| match key{
+                Ok(key) => String::from_utf8(key).ok().map(Ok),
+                Err(e) => Some(Err(e)),
+            })
+            .take(limit);
+        Box::new(keys)
+    }
+
+    /// the keys of arbitrary bytes within `range` in ascending order, see `keys`
+    fn keys_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> KeyBytesIter<'_>;
+
+    /// the keys matching `pattern` in ascending order, at most `limit` of them
+    fn keys_matching(&self, pattern: &Glob, limit: usize) -> KeyIter<'_>{
+        let prefix = pattern.literal_prefix();
+        let pattern = pattern.clone();
+        let keys = self.keys(prefix.clone().., usize::MAX)
+            .take_while(move |key| match key{
+                Ok(key) => key.starts_with(&prefix),
+                Err(_) => true,
+            })
+            .filter(move |key| match key{
+                Ok(key) => pattern.matches(key),
+                Err(_) => true,
+            })
+            .take(limit);
+        Box::new(keys)
+    }
+
+    /// the number of keys, walking all of them
+    fn count(&self) -> Result<u64>;
+
+    /// reclaim the space of overwritten and removed values now
+    fn compact(&self) -> Result<()>;
+
+    /// apply every operation of `batch` atomically
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// set `key` to `new`, or remove it if `new` is None, only if its current value is `expected`,
+    /// None meaning the key does not exist. returns whether the swap happened
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool>;
+
+    /// set `key` only if it does not exist yet, returns whether it was set
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool>{
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// set `key` only if it already exists, returns whether it was set
+    fn set_if_present(&self, key: String, value: String) -> Result<bool>;
+
+    /// change the signed 64-bit integer stored at `key` atomically as `op` says and return the result.
+    /// a missing key counts as 0, an existing key keeps its expiry
+    fn update_integer(&self, key: String, op: IntegerOp) -> Result<i64>;
+
+    /// add `delta` to the integer stored at `key`, see `update_integer`
+    fn incr(&self, key: String, delta: i64) -> Result<i64>{
+        self.update_integer(key, IntegerOp::Incr(delta))
+    }
+
+    /// subtract `delta` from the integer stored at `key`, see `update_integer`
+    fn decr(&self, key: String, delta: i64) -> Result<i64>{
+        self.update_integer(key, IntegerOp::Decr(delta))
+    }
+
+    /// start an optimistic transaction
+    fn begin(&self) -> Transaction<Self> where Self: Sized{
+        Transaction::new(self.clone())
+    }
+
+    /// get a value by key along with its current version, for `Transaction::get`
+    fn read_for_transaction(&self, key: &str) -> Result<(Option<String>, Self::ReadVersion)>;
+
+    /// apply `writes` atomically if every key in `reads` still has the version it was read with,
+    /// fail with `KvsError::Conflict` otherwise
+    fn commit_transaction(&self, reads: Vec<(String, Self::ReadVersion)>, writes: WriteBatch) -> Result<()>;
+}
+
+/// `(key, value)` pairs in ascending key order, from `KvsEngine::scan`
+pub type ScanIter<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// keys in ascending order, from `KvsEngine::keys`
+pub type KeyIter<'a> = Box<dyn Iterator<Item = Result<String>> + 'a>;
+
+/// `(key, value)` pairs of arbitrary bytes in ascending key order, from `KvsEngine::scan_bytes`
+pub type ScanBytesIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// keys of arbitrary bytes in ascending order, from `KvsEngine::keys_bytes`
+pub type KeyBytesIter<'a> = Box<dyn Iterator<Item = Result<Vec<u8>>> + 'a>;
+
+/// a range of strings as the range of their bytes, which sort the same
+fn byte_range<R: RangeBounds<String>>(range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>){
+    let bytes = |key: &String| key.as_bytes().to_vec();
+    (range.start_bound().map(bytes), range.end_bound().map(bytes))
+}
+
+/// milliseconds since the unix epoch, the unit expiries are stored in
+fn unix_millis() -> u64{
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// when a key written now with `ttl` expires
+fn expiry_after(ttl: Duration) -> u64{
+    unix_millis().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+/// the time left until `expires_at`
+fn time_left(expires_at: u64) -> Duration{
+    Duration::from_millis(expires_at.saturating_sub(unix_millis()))
+}
+
+/// how `KvsEngine::update_integer` changes an integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegerOp{
+    Incr(i64),
+    Decr(i64),
+}
+
+/// the integer stored at `key` changed by `op`, for `KvsEngine::update_integer`
+fn update_value(key: &str, value: Option<&[u8]>, op: IntegerOp) -> Result<i64>{
+    let current = match value{
+        Some(value) => std::str::from_utf8(value).ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(|| KvsError::NotAnInteger(key.to_owned()))?,
+        None => 0,
+    };
+    let updated = match op{
+        IntegerOp::Incr(delta) => current.checked_add(delta),
+        IntegerOp::Decr(delta) => current.checked_sub(delta),
+    };
+    updated.ok_or_else(|| KvsError::Overflow(key.to_owned()))
 }
 
+mod batch;
+mod durability;
 mod kvs;
 mod sled;
+mod transaction;
 
-pub use self::kvs::KvStore;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::durability::Durability;
+pub use self::kvs::{CompactionPolicy, KvStore, KvStoreBuilder};
+pub use self::kvs::KeyVersion;
 pub use self::sled::SledStore;
+pub use self::transaction::Transaction;
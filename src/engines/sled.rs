@@ -1,46 +1,374 @@
-use crate::{KvsError, Result};
-use super::KvsEngine;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+use log::error;
+use crate::{Durability, KvsError, Result};
+use super::{expiry_after, time_left, unix_millis, update_value, BatchOp, IntegerOp, KeyBytesIter, KvsEngine, ScanBytesIter, WriteBatch};
 
-use sled::Db;
+use sled::{ConflictableTransactionError, Db, IVec, TransactionError};
 
-pub struct SledStore(Db);
+/// first byte of a value stored with an expiry, followed by the u64 time it expires at and the value.
+/// it never starts a utf-8 string, so values written without an expiry are stored as they are
+const EXPIRING: u8 = 0xff;
+
+/// first byte of a value without an expiry that starts with `EXPIRING` or `ESCAPED` itself, followed by the value.
+/// it never starts a utf-8 string either
+const ESCAPED: u8 = 0xfe;
+
+/// how often the expirer looks for expired keys
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// keys the expirer looks at per round, the next round carries on after them
+const EXPIRE_SCAN_LEN: usize = 10_000;
+
+#[derive(Clone)]
+pub struct SledStore{
+    /// the expirer only holds it weakly, so the db closes with the last clone
+    db: Arc<Db>,
+    durability: Durability,
+    /// writes since the tree was last flushed, for `Durability::EveryN`
+    unflushed: Arc<AtomicU64>,
+}
+
+/// a value read from the tree, with the time it expires at in milliseconds since the unix epoch
+struct Stored{
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+}
 
 impl SledStore{
+    /// wrap an open db with `Durability::OsBuffered`, which flushes every write on sled
     pub fn new(db: Db) -> Self{
-        SledStore(db)
+        let store = SledStore{
+            db: Arc::new(db),
+            durability: Durability::OsBuffered,
+            unflushed: Arc::new(AtomicU64::new(0)),
+        };
+        // expired keys stay hidden without the expirer, they are only not removed
+        if let Err(e) = spawn_expirer(Arc::downgrade(&store.db)){
+            error!("starting the expirer failed: {}", e);
+        }
+        store
+    }
+
+    /// open the db in `path`, flushing writes as `durability` asks.
+    /// sled buffers writes in the process and cannot hand them to the operating system without syncing them,
+    /// so `Durability::OsBuffered` flushes every write just like `Durability::Always`
+    pub fn open(path: impl AsRef<Path>, durability: Durability) -> Result<Self>{
+        let mut config = sled::Config::new().path(path);
+        if let Durability::Interval(interval) = durability{
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        let db = Arc::new(config.open()?);
+        spawn_expirer(Arc::downgrade(&db))?;
+        Ok(SledStore{
+            db,
+            durability,
+            unflushed: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// the bytes stored at `key`, to compare and swap them, and the value they hold unless it expired
+    fn load(&self, key: &[u8]) -> Result<(Option<IVec>, Option<Stored>)>{
+        let bytes = self.db.get(key)?;
+        let stored = bytes.as_deref().map(decode_value).filter(|stored| !is_expired(stored.expires_at));
+        Ok((bytes, stored))
+    }
+
+    /// flush after a write if the durability setting asks for it
+    fn written(&self) -> Result<()>{
+        let flush = match self.durability{
+            Durability::Always | Durability::OsBuffered => true,
+            Durability::EveryN(n) => {
+                let unflushed = self.unflushed.fetch_add(1, Ordering::SeqCst) + 1;
+                if unflushed >= n{
+                    self.unflushed.store(0, Ordering::SeqCst);
+                }
+                unflushed >= n
+            },
+            Durability::Interval(_) => false,
+        };
+        if flush{
+            self.db.flush()?;
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledStore{
-    fn set(&mut self, key: String, value: String) -> Result<()>{
-        let tree = &self.0;
-        tree.insert(key, value.into_bytes())?;
-        tree.flush()?;
+    /// the value read, sled's transactions compare it at commit
+    type ReadVersion = Option<Vec<u8>>;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>{
+        self.db.insert(key, encode_value(&value, None))?;
+        self.written()
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>{
+        self.db.insert(key, encode_value(value.as_bytes(), Some(expiry_after(ttl))))?;
+        self.written()
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>{
+        Ok(self.load(&key)?.1.map(|stored| stored.value))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>{
+        let v = self.db.remove(key)?;
+        match v{
+            Some(ref bytes) if !is_expired(expiry_of(bytes)) => self.written(),
+            _ => Err(KvsError::NotFound("key not found".to_owned())),
+        }
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>{
+        match self.load(&key)?.1{
+            Some(stored) => Ok(stored.expires_at.map(time_left)),
+            None => Err(KvsError::NotFound("key not found".to_owned())),
+        }
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<bool>{
+        loop{
+            let (bytes, stored) = self.load(&key)?;
+            let value = match stored{
+                Some(Stored{ expires_at: None, .. }) => return Ok(false),
+                Some(stored) => stored.value,
+                None => return Err(KvsError::NotFound("key not found".to_owned())),
+            };
+            if self.db.compare_and_swap(&key, bytes, Some(encode_value(&value, None)))?.is_ok(){
+                self.written()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> ScanBytesIter<'_>{
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let entries = self.db.range(range)
+            .filter_map(|entry| match entry{
+                Ok((_, ref bytes)) if is_expired(expiry_of(bytes)) => None,
+                Ok((key, bytes)) => Some(Ok((key.to_vec(), decode_value(&bytes).value))),
+                Err(e) => Some(Err(e.into())),
+            })
+            .take(limit);
+        Box::new(entries)
+    }
+
+    fn exists_bytes(&self, key: Vec<u8>) -> Result<bool>{
+        let bytes = self.db.get(key)?;
+        Ok(bytes.is_some_and(|bytes| !is_expired(expiry_of(&bytes))))
+    }
+
+    fn keys_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: usize) -> KeyBytesIter<'_>{
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let keys = self.db.range(range)
+            .filter_map(|entry| match entry{
+                Ok((_, ref bytes)) if is_expired(expiry_of(bytes)) => None,
+                Ok((key, _)) => Some(Ok(key.to_vec())),
+                Err(e) => Some(Err(e.into())),
+            })
+            .take(limit);
+        Box::new(keys)
+    }
+
+    fn count(&self) -> Result<u64>{
+        self.db.iter().try_fold(0, |count, entry| {
+            let (_, bytes) = entry?;
+            Ok(if is_expired(expiry_of(&bytes)){ count }else{ count + 1 })
+        })
+    }
+
+    /// sled reclaims space in the background on its own
+    fn compact(&self) -> Result<()>{
         Ok(())
     }
-    fn get(&mut self, key: String) -> Result<Option<String>>{
-        let tree = &self.0;
-        let arr = tree.get(key.into_bytes())?;
-        tree.flush()?;
-        match arr{
-            Some(v) => {
-                let s =String::from_utf8(v.to_vec());
-                match s{
-                    Ok(s) => Ok(Some(s)),
-                    Err(e) => Err(KvsError::FromUtf8Error(e)),
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>{
+        let mut sled_batch = sled::Batch::default();
+        for op in batch.into_ops(){
+            match op{
+                BatchOp::Set(key, value) => sled_batch.insert(key.as_bytes(), value.into_bytes()),
+                BatchOp::Remove(key) => sled_batch.remove(key.as_bytes()),
+            }
+        }
+        self.db.apply_batch(sled_batch)?;
+        self.written()
+    }
+
+    // the conditional writes compare the stored bytes rather than the value,
+    // and retry if another writer changed them between the read and the swap
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool>{
+        loop{
+            let (bytes, stored) = self.load(key.as_bytes())?;
+            if stored.map(|stored| stored.value).as_deref() != expected.as_ref().map(String::as_bytes){
+                return Ok(false);
+            }
+            if self.db.compare_and_swap(&key, bytes, new.as_deref().map(str::as_bytes))?.is_ok(){
+                self.written()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool>{
+        loop{
+            let (bytes, stored) = self.load(key.as_bytes())?;
+            if stored.is_none(){
+                return Ok(false);
+            }
+            if self.db.compare_and_swap(&key, bytes, Some(value.as_bytes()))?.is_ok(){
+                self.written()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn update_integer(&self, key: String, op: IntegerOp) -> Result<i64>{
+        loop{
+            let (bytes, stored) = self.load(key.as_bytes())?;
+            let value = update_value(&key, stored.as_ref().map(|stored| stored.value.as_slice()), op)?;
+            let new = encode_value(value.to_string().as_bytes(), stored.and_then(|stored| stored.expires_at));
+            if self.db.compare_and_swap(&key, bytes, Some(new))?.is_ok(){
+                self.written()?;
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_for_transaction(&self, key: &str) -> Result<(Option<String>, Option<Vec<u8>>)>{
+        let value = self.get_bytes(key.as_bytes().to_vec())?;
+        let decoded = value.clone().map(String::from_utf8).transpose()?;
+        Ok((decoded, value))
+    }
+
+    fn commit_transaction(&self, reads: Vec<(String, Option<Vec<u8>>)>, writes: WriteBatch) -> Result<()>{
+        let result = self.db.transaction(|tx| {
+            for (key, seen) in &reads{
+                let current = tx.get(key.as_bytes())?;
+                let changed = match current.as_deref().map(decode_value){
+                    Some(stored) if !is_expired(stored.expires_at) => seen.as_ref() != Some(&stored.value),
+                    _ => seen.is_some(),
+                };
+                if changed{
+                    return Err(ConflictableTransactionError::Abort(key.clone()));
                 }
-            },
-            None => Ok(None)
+            }
+            for op in writes.ops(){
+                match op{
+                    BatchOp::Set(key, value) => {
+                        tx.insert(key.as_bytes(), value.as_bytes())?;
+                    },
+                    BatchOp::Remove(key) => {
+                        tx.remove(key.as_bytes())?;
+                    },
+                }
+            }
+            Ok(())
+        });
+
+        match result{
+            Ok(()) => self.written(),
+            Err(TransactionError::Abort(key)) => Err(KvsError::Conflict(key)),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
         }
     }
+}
+
+/// the bytes to store for `value` expiring at `expires_at`
+fn encode_value(value: &[u8], expires_at: Option<u64>) -> Vec<u8>{
+    match expires_at{
+        Some(at) => {
+            let mut bytes = Vec::with_capacity(9 + value.len());
+            bytes.push(EXPIRING);
+            bytes.extend_from_slice(&at.to_be_bytes());
+            bytes.extend_from_slice(value);
+            bytes
+        },
+        None if value.first().is_some_and(|&first| first == EXPIRING || first == ESCAPED) => {
+            let mut bytes = Vec::with_capacity(1 + value.len());
+            bytes.push(ESCAPED);
+            bytes.extend_from_slice(value);
+            bytes
+        },
+        None => value.to_vec(),
+    }
+}
+
+fn decode_value(bytes: &[u8]) -> Stored{
+    let expires_at = expiry_of(bytes);
+    let value = match bytes.first(){
+        _ if expires_at.is_some() => &bytes[9..],
+        Some(&ESCAPED) => &bytes[1..],
+        _ => bytes,
+    };
+    Stored{ value: value.to_vec(), expires_at }
+}
+
+/// the time stored bytes expire at, without decoding the value
+fn expiry_of(bytes: &[u8]) -> Option<u64>{
+    if bytes.len() < 9 || bytes[0] != EXPIRING{
+        return None;
+    }
+    let mut at = [0; 8];
+    at.copy_from_slice(&bytes[1..9]);
+    Some(u64::from_be_bytes(at))
+}
+
+fn is_expired(expires_at: Option<u64>) -> bool{
+    matches!(expires_at, Some(at) if at <= unix_millis())
+}
+
+/// remove expired keys every `EXPIRE_INTERVAL` until the db is closed
+fn spawn_expirer(db: Weak<Db>) -> Result<()>{
+    thread::Builder::new()
+        .name("sled-expirer".to_owned())
+        .spawn(move || {
+            let mut cursor = Vec::new();
+            loop{
+                thread::sleep(EXPIRE_INTERVAL);
+                let db = match db.upgrade(){
+                    Some(db) => db,
+                    None => break,
+                };
+                match expire(&db, &cursor){
+                    Ok(next) => cursor = next,
+                    Err(e) => error!("expiring keys failed: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
 
-    fn remove(&mut self, key: String) -> Result<()>{
-        let tree = &self.0;
-        let v = tree.remove(key)?;
-        tree.flush()?;
-        if v.is_none(){
-            return Err(KvsError::NotFound("key not found".to_owned()));
+/// remove the expired keys among the `EXPIRE_SCAN_LEN` keys from `cursor` on.
+/// returns the key the next round starts from, the first one once the end of the tree was reached
+fn expire(db: &Db, cursor: &[u8]) -> Result<Vec<u8>>{
+    let mut last = None;
+    let mut scanned = 0;
+    for entry in db.range(cursor..){
+        let (key, bytes) = entry?;
+        if is_expired(expiry_of(&bytes)){
+            // a value written since it was read is left alone
+            let _ = db.compare_and_swap(&key, Some(&bytes), None as Option<&[u8]>)?;
         }
-        Ok(())
+        last = Some(key);
+        scanned += 1;
+        if scanned == EXPIRE_SCAN_LEN{
+            break;
+        }
+    }
+
+    match last{
+        // the smallest key after the last one scanned
+        Some(key) if scanned == EXPIRE_SCAN_LEN => {
+            let mut next = key.to_vec();
+            next.push(0);
+            Ok(next)
+        },
+        _ => Ok(Vec::new()),
     }
 }
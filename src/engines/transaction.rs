@@ -0,0 +1,99 @@
+use std::collections::btree_map::{BTreeMap, Entry};
+use crate::{KvsError, Result};
+use super::{update_value, IntegerOp, KvsEngine, WriteBatch};
+
+/// an optimistic transaction.
+/// reads go to the engine and are remembered with the version they saw, writes are buffered.
+/// `commit` applies the writes atomically, or fails with `KvsError::Conflict`
+/// if a key the transaction read was written by someone else in the meantime
+pub struct Transaction<E: KvsEngine>{
+    engine: E,
+    /// the first version seen of every key read from the engine
+    reads: BTreeMap<String, E::ReadVersion>,
+    /// the last write to every key, None for a remove
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl<E: KvsEngine> Transaction<E>{
+    pub fn new(engine: E) -> Self{
+        Transaction{
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// get a value by key, seeing the writes of this transaction
+    pub fn get(&mut self, key: String) -> Result<Option<String>>{
+        if let Some(write) = self.writes.get(&key){
+            return Ok(write.clone());
+        }
+
+        let (value, version) = self.engine.read_for_transaction(&key)?;
+        match self.reads.entry(key){
+            Entry::Vacant(entry) => {
+                entry.insert(version);
+            },
+            // keep the first version so a change between two reads is caught at commit
+            Entry::Occupied(_) => {},
+        }
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: String, value: String){
+        self.writes.insert(key, Some(value));
+    }
+
+    /// remove a key, failing like `KvsEngine::remove` if it does not exist.
+    /// the key counts as read, so the transaction conflicts if someone else sets or removes it
+    pub fn remove(&mut self, key: String) -> Result<()>{
+        if self.get(key.clone())?.is_none(){
+            return Err(KvsError::NotFound("Key not found".to_owned()));
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// set or remove a key if it holds the expected value, see `KvsEngine::compare_and_swap`.
+    /// the key counts as read, so the comparison still holds when the transaction commits
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool>{
+        if self.get(key.clone())? != expected{
+            return Ok(false);
+        }
+        if new.is_some() || expected.is_some(){
+            self.writes.insert(key, new);
+        }
+        Ok(true)
+    }
+
+    /// add `delta` to the integer stored at a key, see `KvsEngine::incr`
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64>{
+        self.update_integer(key, IntegerOp::Incr(delta))
+    }
+
+    /// subtract `delta` from the integer stored at a key, see `KvsEngine::decr`
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64>{
+        self.update_integer(key, IntegerOp::Decr(delta))
+    }
+
+    fn update_integer(&mut self, key: String, op: IntegerOp) -> Result<i64>{
+        let value = update_value(&key, self.get(key.clone())?.as_deref().map(str::as_bytes), op)?;
+        self.writes.insert(key, Some(value.to_string()));
+        Ok(value)
+    }
+
+    /// apply the buffered writes if no key read by the transaction changed since it was read
+    pub fn commit(self) -> Result<()>{
+        let mut batch = WriteBatch::new();
+        for (key, write) in self.writes{
+            match write{
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.engine.commit_transaction(self.reads.into_iter().collect(), batch)
+    }
+
+    /// drop the buffered writes
+    pub fn abort(self){}
+}
@@ -1,18 +1,114 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum KvsError {
-    #[error("standard io error")]
+    #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 
+    /// an io error on a file of the data directory, at the offset of a record if it was about one
+    #[error("io error on {}{}: {source}", path.display(), at_offset(offset))]
+    FileIo{ path: PathBuf, offset: Option<u64>, source: io::Error },
+
+    #[error("invalid data directory {}: {1}", .0.display())]
+    InvalidPath(PathBuf, String),
+
     #[error("Key not found")]
     NotFound(String),
 
-    #[error("sled error")]
+    #[error("sled error: {0}")]
     SledError(#[from] sled::Error),
 
-    #[error("from utf8 error")]
+    #[error("not utf-8: {0}")]
     FromUtf8Error(#[from] std::string::FromUtf8Error),
+
+    #[error("serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+
+    #[error("frame of {0} bytes exceeds the limit of {1} bytes")]
+    FrameTooLarge(usize, usize),
+
+    #[error("connection closed by peer")]
+    ConnectionClosed,
+
+    #[error("thread pool error: {0}")]
+    ThreadPool(String),
+
+    #[error("compaction failed: {0}")]
+    Compaction(String),
+
+    #[error("corrupted log: {0}")]
+    Corrupted(Corruption),
+
+    #[error("{}: unsupported log format version {1}", .0.display())]
+    UnsupportedVersion(PathBuf, u32),
+
+    #[error("transaction conflict on key {0}")]
+    Conflict(String),
+
+    #[error("value of key {0} is not a 64-bit integer")]
+    NotAnInteger(String),
+
+    #[error("integer overflow on key {0}")]
+    Overflow(String),
+
+    #[error("the store is read-only, it cannot {0}")]
+    ReadOnly(String),
+
+    #[error("{} is locked by {}", .0.display(), holder(.1))]
+    Locked(PathBuf, Option<u32>),
+
+    /// a thread panicked while it held the named lock, what it guards may be half updated
+    #[error("a thread panicked holding the {0} lock")]
+    Poisoned(&'static str),
+}
+
+impl KvsError{
+    /// turns an io error on the file at `path` into `KvsError::FileIo`, for `map_err`
+    pub(crate) fn file(path: impl Into<PathBuf>, offset: Option<u64>) -> impl FnOnce(io::Error) -> KvsError{
+        let path = path.into();
+        move |source| KvsError::FileIo{ path, offset, source }
+    }
+
+    /// whether the error is an io error of `kind`, with or without a file to it
+    pub fn is_io(&self, kind: io::ErrorKind) -> bool{
+        match self{
+            KvsError::Io(e) | KvsError::FileIo{ source: e, .. } => e.kind() == kind,
+            _ => false,
+        }
+    }
+}
+
+/// where a log is damaged and how
+#[derive(Debug)]
+pub struct Corruption{
+    /// the log file, relative to the data directory
+    pub file: PathBuf,
+    /// where the damaged record starts
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for Corruption{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{} at offset {}: {}", self.file.display(), self.offset, self.reason)
+    }
+}
+
+fn at_offset(offset: &Option<u64>) -> String{
+    match offset{
+        Some(offset) => format!(" at offset {}", offset),
+        None => String::new(),
+    }
+}
+
+fn holder(pid: &Option<u32>) -> String{
+    match pid{
+        Some(pid) => format!("process {}", pid),
+        None => "another process".to_owned(),
+    }
 }
 
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,127 @@
+use std::str::Chars;
+
+/// a glob pattern over keys, for `KvsEngine::keys_matching`.
+/// `*` matches any run of characters, `?` any one character, `[abc]` or `[a-z]` one character of the set
+/// and `[!abc]` or `[^abc]` one character not in it; `\` makes the next character match only itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob{
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token{
+    Char(char),
+    AnyChar,
+    AnyRun,
+    /// inclusive character ranges, a single character being a range of one
+    Class{ negated: bool, ranges: Vec<(char, char)> },
+}
+
+impl Glob{
+    /// every pattern is valid: a `[` without its `]` matches itself
+    pub fn new(pattern: &str) -> Glob{
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next(){
+            let token = match c{
+                '*' => Token::AnyRun,
+                '?' => Token::AnyChar,
+                '\\' => Token::Char(chars.next().unwrap_or('\\')),
+                '[' => match parse_class(chars.clone()){
+                    Some((class, rest)) => {
+                        chars = rest;
+                        class
+                    },
+                    None => Token::Char('['),
+                },
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+        Glob{ tokens }
+    }
+
+    /// whether the whole of `key` matches the pattern
+    pub fn matches(&self, key: &str) -> bool{
+        let key: Vec<char> = key.chars().collect();
+        let (mut t, mut k) = (0, 0);
+        // where to resume after the last `*` when the rest fails to match: the token after it,
+        // and the first key character it does not cover yet
+        let mut resume = None;
+        while k < key.len(){
+            match self.tokens.get(t){
+                Some(Token::AnyRun) => {
+                    t += 1;
+                    resume = Some((t, k));
+                    continue;
+                },
+                Some(token) if token.matches(key[k]) => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                },
+                _ => {},
+            }
+            match resume{
+                Some((after_star, covered)) => {
+                    t = after_star;
+                    k = covered + 1;
+                    resume = Some((after_star, k));
+                },
+                None => return false,
+            }
+        }
+        self.tokens[t..].iter().all(|token| *token == Token::AnyRun)
+    }
+
+    /// the characters every matching key starts with
+    pub fn literal_prefix(&self) -> String{
+        self.tokens.iter()
+            .map_while(|token| match token{
+                Token::Char(c) => Some(*c),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Token{
+    fn matches(&self, c: char) -> bool{
+        match self{
+            Token::Char(expected) => *expected == c,
+            Token::AnyChar | Token::AnyRun => true,
+            Token::Class{ negated, ranges } => {
+                ranges.iter().any(|&(low, high)| low <= c && c <= high) != *negated
+            },
+        }
+    }
+}
+
+/// the class following a `[`, and the characters after its `]`. None if the class is not closed
+fn parse_class(mut chars: Chars) -> Option<(Token, Chars)>{
+    let mut negated = false;
+    let mut ahead = chars.clone();
+    if let Some('!') | Some('^') = ahead.next(){
+        negated = true;
+        chars = ahead;
+    }
+
+    let mut ranges = Vec::new();
+    loop{
+        let low = match chars.next()?{
+            // a `]` right at the start belongs to the class
+            ']' if !ranges.is_empty() => return Some((Token::Class{ negated, ranges }, chars)),
+            '\\' => chars.next()?,
+            c => c,
+        };
+        let mut ahead = chars.clone();
+        let high = match (ahead.next(), ahead.next()){
+            (Some('-'), Some(c)) if c != ']' => {
+                chars = ahead;
+                if c == '\\' { chars.next()? } else { c }
+            },
+            _ => low,
+        };
+        ranges.push((low, high));
+    }
+}
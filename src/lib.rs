@@ -1,35 +1,253 @@
 #![feature(seek_convenience)]
 use serde::{Serialize, Deserialize};
 use chrono::Local;
+use std::fmt;
 use std::io::{self, Write};
+use std::time::Duration;
 
+mod bytes;
+mod client;
 mod engines;
 mod error;
+mod glob;
+mod protocol;
+pub mod thread_pool;
 
-pub use engines::KvStore;
-pub use engines::KvsEngine;
+pub use bytes::Bytes;
+pub use client::KvsClient;
+pub use engines::{CompactionPolicy, Durability, KeyVersion, KvStore, KvStoreBuilder};
+pub use engines::{BatchOp, IntegerOp, KeyBytesIter, KeyIter, KvsEngine, ScanBytesIter, ScanIter, Transaction, WriteBatch};
 pub use engines::SledStore;
-pub use error::{KvsError, Result};
+pub use error::{Corruption, KvsError, Result};
+pub use glob::Glob;
+pub use protocol::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Command{
     Set(String, String),
+    /// set a key that expires once the duration has passed
+    SetWithTtl(String, String, Duration),
+    /// the time a key has left before it expires
+    Ttl(String),
+    /// clear the expiry of a key, answered with whether it had one
+    Persist(String),
     Get(String),
     Rm(String),
+    /// `Set` of a key and value that need not be utf-8
+    SetBytes(Bytes, Bytes),
+    /// `Get` of a key and value that need not be utf-8, answered with `Response::Bytes`
+    GetBytes(Bytes),
+    /// `Rm` of a key that need not be utf-8
+    RmBytes(Bytes),
+    /// `Ttl` of a key that need not be utf-8
+    TtlBytes(Bytes),
+    /// `Persist` of a key that need not be utf-8
+    PersistBytes(Bytes),
+    /// `Exists` of a key that need not be utf-8
+    ExistsBytes(Bytes),
+    /// reclaim the space of stale values now
+    Compact,
+    /// apply several sets and removes atomically
+    Batch(WriteBatch),
+    /// key, expected value and new value, None meaning missing or remove:
+    /// swap only if the key holds the expected value
+    Cas(String, Option<String>, Option<String>),
+    /// set only if the key does not exist
+    SetIfAbsent(String, String),
+    /// set only if the key exists
+    SetIfPresent(String, String),
+    /// list keys in ascending order with their values, a page at a time
+    Scan(ScanRequest),
+    /// list the keys matching a glob pattern in ascending order, a page at a time
+    Keys(KeysRequest),
+    /// the number of keys in the store
+    Count,
+    /// whether a key exists, without sending its value
+    Exists(String),
+    /// add to the integer stored at a key, answered with the new value
+    Incr(String, i64),
+    /// subtract from the integer stored at a key, answered with the new value
+    Decr(String, i64),
+    /// start a transaction on this connection: the gets, sets, removes and batches
+    /// that follow belong to it until `Commit` or `Abort`
+    Begin,
+    /// apply the writes of the transaction, unless a key it read changed in the meantime
+    Commit,
+    /// drop the writes of the transaction
+    Abort,
 }
 
-#[derive(Serialize, Deserialize)]
+impl Command{
+    /// whether the command changes the store, a server serving a read-only store rejects those
+    pub fn is_write(&self) -> bool{
+        match self{
+            Command::Set(..) | Command::SetWithTtl(..) | Command::Persist(_) | Command::Rm(_)
+            | Command::SetBytes(..) | Command::RmBytes(_) | Command::PersistBytes(_) | Command::Compact | Command::Batch(_)
+            | Command::Cas(..) | Command::SetIfAbsent(..) | Command::SetIfPresent(..)
+            | Command::Incr(..) | Command::Decr(..) => true,
+            Command::Ttl(_) | Command::Get(_) | Command::GetBytes(_) | Command::TtlBytes(_) | Command::ExistsBytes(_)
+            | Command::Scan(_) | Command::Keys(_) | Command::Count | Command::Exists(_)
+            | Command::Begin | Command::Commit | Command::Abort => false,
+        }
+    }
+}
+
+/// one page of the keys within a range and with a prefix.
+/// the next page is requested with `start` set to the key the previous page returned for it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ScanRequest{
+    /// first key, None starts at the first key of the store
+    pub start: Option<String>,
+    /// key the scan stops before, None runs to the last key of the store
+    pub end: Option<String>,
+    /// only keys starting with it
+    pub prefix: Option<String>,
+    /// most keys in the page, the server may return fewer
+    pub limit: usize,
+}
+
+/// one page of the keys matching a glob pattern, see `kvs::Glob`.
+/// the next page is requested with `start` set to the key the previous page returned for it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KeysRequest{
+    pub pattern: String,
+    /// first key to look at, None starts at the first key of the store
+    pub start: Option<String>,
+    /// most keys in the page. the server looks at a bounded number of keys per page,
+    /// so a page may hold fewer keys even when more follow
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Response{
     Null,
     Value(String),
+    /// the value of `Command::GetBytes`
+    Bytes(Bytes),
+    /// whether a conditional write took effect, or whether a key exists
+    Bool(bool),
+    /// the result of an increment or decrement, or a count
+    Integer(i64),
+    /// the time a key has left before it expires, None if it does not expire
+    Ttl(Option<Duration>),
+    /// a page of a scan, and the key the next page starts at if there is one
+    Page(Vec<(String, String)>, Option<String>),
+    /// a page of keys, and the key the next page starts at if there is one
+    Keys(Vec<String>, Option<String>),
     Error(ServerError),
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum ServerError{
+/// an error the server answers a command with: what kind of error it is, a message for people
+/// and whether the same command may succeed if sent again unchanged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerError{
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode{
     NotFound,
     InvalidCommand,
-    OtherError,
+    FrameTooLarge,
+    /// a key the transaction read changed before it committed
+    Conflict,
+    /// the value is not a 64-bit integer
+    NotAnInteger,
+    /// the result does not fit in a 64-bit integer
+    Overflow,
+    /// the value is not valid utf-8 and was asked for as a string
+    NotUtf8,
+    /// the server serves a read-only store and the command would change it
+    ReadOnly,
+    /// a log or hint file of the store is corrupt or of an unknown version
+    Corrupted,
+    /// the disk of the store is full
+    StorageFull,
+    /// reading or writing the files of the store failed
+    Storage,
+    /// anything else that went wrong on the server
+    Internal,
+}
+
+impl ErrorCode{
+    /// what the error is, in a few words
+    pub fn description(self) -> &'static str{
+        match self{
+            ErrorCode::NotFound => "Key not found",
+            ErrorCode::InvalidCommand => "Invalid command",
+            ErrorCode::FrameTooLarge => "Request too large",
+            ErrorCode::Conflict => "Transaction conflict",
+            ErrorCode::NotAnInteger => "Value is not an integer",
+            ErrorCode::Overflow => "Integer overflow",
+            ErrorCode::NotUtf8 => "Value is not valid utf-8",
+            ErrorCode::ReadOnly => "The server is read-only",
+            ErrorCode::Corrupted => "The store on the server is corrupted",
+            ErrorCode::StorageFull => "The disk of the server is full",
+            ErrorCode::Storage => "The server failed to read or write its store",
+            ErrorCode::Internal => "Server error",
+        }
+    }
+}
+
+impl ServerError{
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self{
+        ServerError{ code, message: message.into(), retryable: code == ErrorCode::Conflict }
+    }
+}
+
+/// the error with the description of the code as its message
+impl From<ErrorCode> for ServerError{
+    fn from(code: ErrorCode) -> Self{
+        ServerError::new(code, code.description())
+    }
+}
+
+impl From<KvsError> for ServerError{
+    fn from(e: KvsError) -> Self{
+        let code = match e{
+            KvsError::NotFound(_) => return ErrorCode::NotFound.into(),
+            KvsError::FrameTooLarge(..) => ErrorCode::FrameTooLarge,
+            KvsError::Conflict(_) => ErrorCode::Conflict,
+            KvsError::NotAnInteger(_) => ErrorCode::NotAnInteger,
+            KvsError::Overflow(_) => ErrorCode::Overflow,
+            KvsError::FromUtf8Error(_) => ErrorCode::NotUtf8,
+            KvsError::ReadOnly(_) => ErrorCode::ReadOnly,
+            KvsError::Corrupted(_) | KvsError::UnsupportedVersion(..) => ErrorCode::Corrupted,
+            // a record or hint the engine cannot encode or decode
+            KvsError::Serde(_) => ErrorCode::Storage,
+            KvsError::SledError(sled::Error::Corruption{ .. }) => ErrorCode::Corrupted,
+            KvsError::Io(ref io) | KvsError::FileIo{ source: ref io, .. }
+            | KvsError::SledError(sled::Error::Io(ref io)) => {
+                if io.kind() == io::ErrorKind::StorageFull{
+                    ErrorCode::StorageFull
+                }else{
+                    ErrorCode::Storage
+                }
+            },
+            KvsError::SledError(_) | KvsError::InvalidPath(..) | KvsError::ConnectionClosed
+            | KvsError::ThreadPool(_) | KvsError::Compaction(_) | KvsError::Locked(..)
+            | KvsError::Poisoned(_) => ErrorCode::Internal,
+        };
+        // an interrupted or timed out io may well go through the next time
+        let retryable = code == ErrorCode::Conflict
+            || e.is_io(io::ErrorKind::Interrupted)
+            || e.is_io(io::ErrorKind::TimedOut);
+        ServerError{ code, message: e.to_string(), retryable }
+    }
+}
+
+/// the description of the code, followed by the message if it tells more
+impl fmt::Display for ServerError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        let description = self.code.description();
+        if self.message.is_empty() || self.message.eq_ignore_ascii_case(description){
+            write!(f, "{}", description)
+        }else{
+            write!(f, "{}: {}", description, self.message)
+        }
+    }
 }
 
 #[derive(PartialEq,Debug)]
@@ -38,6 +256,14 @@ pub enum Engine{
     Sled,
 }
 
+/// the `ThreadPool` implementation a server runs its connections on
+#[derive(PartialEq,Debug)]
+pub enum Pool{
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
 pub fn log_init(){
     let env = env_logger::Env::default()
         .filter_or(env_logger::DEFAULT_FILTER_ENV, "trace");
@@ -0,0 +1,60 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::io::{self, Read, Write};
+use crate::{KvsError, Result};
+
+/// every frame starts with its payload length as a big-endian u32
+pub const HEADER_LEN: usize = 4;
+
+/// default upper bound of a frame payload: 64 MiB
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// serialize `msg` as json and write it as one length-prefixed frame.
+/// the writer is not flushed, so several frames can be sent in one go
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> Result<()>{
+    let payload = serde_json::to_vec(msg)?;
+    if payload.len() > u32::MAX as usize{
+        return Err(KvsError::FrameTooLarge(payload.len(), u32::MAX as usize));
+    }
+
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// read one length-prefixed frame and deserialize its payload.
+/// returns `Ok(None)` if the peer closed the stream before a new frame started.
+///
+/// a frame longer than `max_size` is skipped without being buffered,
+/// so the stream stays usable for the next frame
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R, max_size: usize) -> Result<Option<T>>{
+    let mut header = [0; HEADER_LEN];
+    if !read_header(reader, &mut header)?{
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_size{
+        io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+        return Err(KvsError::FrameTooLarge(len, max_size));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    let msg = serde_json::from_slice(&payload)?;
+    Ok(Some(msg))
+}
+
+/// fill the header, returns false on EOF before the first byte
+fn read_header<R: Read>(reader: &mut R, header: &mut [u8; HEADER_LEN]) -> Result<bool>{
+    let mut filled = 0;
+    while filled < HEADER_LEN{
+        match reader.read(&mut header[filled..]){
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
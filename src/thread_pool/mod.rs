@@ -0,0 +1,19 @@
+use crate::Result;
+
+/// a pool of threads running jobs submitted with `spawn`.
+/// a panicking job must not reduce the number of threads available to the pool
+pub trait ThreadPool{
+    /// create a pool with `threads` worker threads
+    fn new(threads: u32) -> Result<Self> where Self: Sized;
+
+    /// run `job` on one of the pool's threads
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
+}
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
@@ -0,0 +1,16 @@
+use std::thread;
+use crate::Result;
+use super::ThreadPool;
+
+/// not really a pool: every job gets a thread of its own
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool{
+    fn new(_threads: u32) -> Result<Self>{
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static{
+        thread::spawn(job);
+    }
+}
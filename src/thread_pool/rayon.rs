@@ -0,0 +1,22 @@
+use log::error;
+use crate::{KvsError, Result};
+use super::ThreadPool;
+
+/// work-stealing pool backed by rayon
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool{
+    fn new(threads: u32) -> Result<Self>{
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // rayon aborts the process on a panicking job unless a handler is set
+            .panic_handler(|_| error!("a job panicked in the rayon thread pool"))
+            .build()
+            .map_err(|e| KvsError::ThreadPool(e.to_string()))?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static{
+        self.0.spawn(job)
+    }
+}
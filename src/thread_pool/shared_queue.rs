@@ -0,0 +1,59 @@
+use std::thread;
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{debug, error};
+use crate::Result;
+use super::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// a fixed number of workers taking jobs from one shared queue.
+/// a worker killed by a panicking job is replaced by a new one
+pub struct SharedQueueThreadPool{
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool{
+    fn new(threads: u32) -> Result<Self>{
+        let (sender, receiver) = channel::unbounded::<Job>();
+        for _ in 0..threads{
+            let worker = Worker(receiver.clone());
+            thread::Builder::new().spawn(move || run_jobs(worker))?;
+        }
+        Ok(SharedQueueThreadPool{ sender })
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static{
+        self.sender
+            .send(Box::new(job))
+            .expect("the thread pool has no thread");
+    }
+}
+
+/// owns the receiving side of the queue for one worker thread,
+/// dropping it during a panic spawns the replacement worker
+#[derive(Clone)]
+struct Worker(Receiver<Job>);
+
+impl Drop for Worker{
+    fn drop(&mut self){
+        if thread::panicking(){
+            let worker = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || run_jobs(worker)){
+                error!("failed to respawn worker thread: {}", e);
+            }
+        }
+    }
+}
+
+fn run_jobs(worker: Worker){
+    loop{
+        match worker.0.recv(){
+            Ok(job) => job(),
+            Err(_) => {
+                // the pool was dropped
+                debug!("thread exits because the pool is destroyed");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use kvs::Bytes;
+
+fn encode(bytes: &[u8]) -> String {
+    serde_json::to_string(&Bytes(bytes.to_vec())).unwrap()
+}
+
+fn decode(text: &str) -> serde_json::Result<Bytes> {
+    serde_json::from_str(&format!("\"{}\"", text))
+}
+
+// Bytes should go over the wire as padded base64, the test vectors of RFC 4648
+#[test]
+fn base64_test_vectors() {
+    let vectors = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+    for (plain, encoded) in vectors.iter() {
+        assert_eq!(encode(plain.as_bytes()), format!("\"{}\"", encoded));
+        assert_eq!(decode(encoded).unwrap(), Bytes(plain.as_bytes().to_vec()));
+    }
+}
+
+// Every byte value and every length of the last group should survive a round trip
+#[test]
+fn base64_round_trip() {
+    let all: Vec<u8> = (0..=255).collect();
+    for len in 0..all.len() {
+        let bytes = &all[all.len() - len..];
+        let text = encode(bytes);
+        assert_eq!(serde_json::from_str::<Bytes>(&text).unwrap(), Bytes(bytes.to_vec()));
+    }
+    assert_eq!(encode(&[0xfb, 0xff]), "\"+/8=\"");
+}
+
+// Text that is not padded base64 should be rejected
+#[test]
+fn base64_rejects_invalid_input() {
+    for text in &["Zg", "Zg=", "Zm9vY", "Z===", "====", "Zg==Zm9v", "Zm=v", "Zm9v!A==", "Zm9v YQ=", "Zm9-"] {
+        assert!(decode(text).is_err(), "{:?} was accepted", text);
+    }
+}
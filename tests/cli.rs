@@ -1,6 +1,9 @@
 use assert_cmd::prelude::*;
+use kvs::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE};
+use kvs::{Bytes, ErrorCode, KeysRequest, KvsClient, Response, ScanRequest, ServerError};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -212,6 +215,30 @@ fn cli_wrong_engine() {
     }
 }
 
+// A second server on the same data directory should refuse to start, naming the process holding it
+#[test]
+fn cli_locked_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4029", "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("is locked by process {}", child.id())));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -336,3 +363,1055 @@ fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
 
+
+// Values far larger than a single socket read should round-trip intact.
+#[test]
+fn cli_large_value() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "v".repeat(64 * 1024);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &value, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Requests above `--max-frame-size` should be rejected without killing the server.
+#[test]
+fn cli_frame_too_large() {
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--max-frame-size", "1024"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "v".repeat(2048);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &value, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Request too large"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// One connection should carry many requests, including pipelined ones,
+// and be closed by the server once `--max-requests` is reached.
+#[test]
+fn persistent_connection() {
+    let addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--max-requests", "6"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let response = client
+        .request(&kvs::Command::Set("key1".to_owned(), "value1".to_owned()))
+        .unwrap();
+    assert_eq!(response, Response::Null);
+    let response = client.request(&kvs::Command::Get("key1".to_owned())).unwrap();
+    assert_eq!(response, Response::Value("value1".to_owned()));
+
+    let responses = client
+        .pipeline(&[
+            kvs::Command::Set("key2".to_owned(), "value2".to_owned()),
+            kvs::Command::Get("key2".to_owned()),
+            kvs::Command::Rm("key1".to_owned()),
+            kvs::Command::Get("key1".to_owned()),
+        ])
+        .unwrap();
+    assert_eq!(
+        responses,
+        vec![
+            Response::Null,
+            Response::Value("value2".to_owned()),
+            Response::Null,
+            Response::Error(ErrorCode::NotFound.into()),
+        ]
+    );
+
+    // the sixth request was the last one allowed on this connection
+    assert!(client.request(&kvs::Command::Get("key2".to_owned())).is_err());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let response = client.request(&kvs::Command::Get("key2".to_owned())).unwrap();
+    assert_eq!(response, Response::Value("value2".to_owned()));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// An idle connection held open by one client must not block other clients.
+#[test]
+fn concurrent_connections() {
+    for (pool, addr) in &[("naive", "127.0.0.1:4009"), ("shared", "127.0.0.1:4010"), ("rayon", "127.0.0.1:4011")] {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&["--engine", "kvs", "--addr", addr, "--pool", pool, "--threads", "2"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let mut idle = KvsClient::connect(addr).unwrap();
+        idle.request(&kvs::Command::Set("key1".to_owned(), "value1".to_owned()))
+            .unwrap();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        drop(idle);
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+// `kvs-client compact` should reclaim space on a server that never compacts on its own.
+#[test]
+fn cli_compact() {
+    let addr = "127.0.0.1:4012";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--compaction", "manual"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..100 {
+        client
+            .request(&kvs::Command::Set("key1".to_owned(), format!("value{}", i)))
+            .unwrap();
+    }
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let logs: Vec<_> = fs::read_dir(temp_dir.path().join("kvstore"))
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .collect();
+    assert!(logs.iter().sum::<u64>() < 100);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value99\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+fn cli_batch(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["batch", "set", "key1", "value1", "set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["batch", "rm", "key1", "set", "key2", "value3", "rm", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["batch", "set", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_batch_kvs_engine() {
+    cli_batch("kvs", "127.0.0.1:4013");
+}
+
+#[test]
+fn cli_batch_sled_engine() {
+    cli_batch("sled", "127.0.0.1:4014");
+}
+
+fn transactions(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut other = KvsClient::connect(addr).unwrap();
+    let set = |key: &str, value: &str| kvs::Command::Set(key.to_owned(), value.to_owned());
+    let get = |key: &str| kvs::Command::Get(key.to_owned());
+
+    assert_eq!(client.request(&set("key1", "1")).unwrap(), Response::Null);
+    assert_eq!(
+        client.request(&kvs::Command::Commit).unwrap(),
+        Response::Error(ErrorCode::InvalidCommand.into())
+    );
+
+    let responses = client
+        .pipeline(&[
+            kvs::Command::Begin,
+            get("key1"),
+            set("key1", "2"),
+            get("key1"),
+            kvs::Command::Commit,
+        ])
+        .unwrap();
+    assert_eq!(
+        responses,
+        vec![
+            Response::Null,
+            Response::Value("1".to_owned()),
+            Response::Null,
+            Response::Value("2".to_owned()),
+            Response::Null,
+        ]
+    );
+    assert_eq!(other.request(&get("key1")).unwrap(), Response::Value("2".to_owned()));
+
+    client.request(&kvs::Command::Begin).unwrap();
+    client.request(&get("key1")).unwrap();
+    client.request(&set("key2", "2")).unwrap();
+    other.request(&set("key1", "3")).unwrap();
+    assert!(matches!(
+        client.request(&kvs::Command::Commit).unwrap(),
+        Response::Error(ServerError {
+            code: ErrorCode::Conflict,
+            retryable: true,
+            ..
+        })
+    ));
+    assert_eq!(
+        other.request(&get("key2")).unwrap(),
+        Response::Error(ErrorCode::NotFound.into())
+    );
+
+    client.request(&kvs::Command::Begin).unwrap();
+    client.request(&set("key2", "2")).unwrap();
+    assert_eq!(client.request(&kvs::Command::Abort).unwrap(), Response::Null);
+    assert_eq!(
+        other.request(&get("key2")).unwrap(),
+        Response::Error(ErrorCode::NotFound.into())
+    );
+
+    drop(client);
+    drop(other);
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn transactions_kvs_engine() {
+    transactions("kvs", "127.0.0.1:4015");
+}
+
+#[test]
+fn transactions_sled_engine() {
+    transactions("sled", "127.0.0.1:4016");
+}
+
+fn conditional_writes(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+        command
+    };
+
+    client(&["set", "key1", "value1", "--xx"])
+        .assert()
+        .failure()
+        .stderr(contains("Key not set"));
+    client(&["set", "key1", "value1", "--nx"]).assert().success();
+    client(&["set", "key1", "value2", "--nx"]).assert().failure();
+    client(&["set", "key1", "value2", "--xx"]).assert().success();
+    client(&["set", "key1", "value3", "--nx", "--xx"]).assert().failure();
+
+    client(&["cas", "key1", "--expect", "value1", "--new", "value3"])
+        .assert()
+        .failure()
+        .stderr(contains("Value mismatch"));
+    client(&["cas", "key1", "--expect", "value2", "--new", "value3"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"]).assert().success().stdout("value3\n");
+
+    client(&["cas", "key2", "--new", "value1"]).assert().success();
+    client(&["cas", "key2", "--expect", "value1"]).assert().success();
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    let mut conn = KvsClient::connect(addr).unwrap();
+    let cas = |key: &str, expected: Option<&str>, new: Option<&str>| {
+        kvs::Command::Cas(key.to_owned(), expected.map(str::to_owned), new.map(str::to_owned))
+    };
+    let responses = conn
+        .pipeline(&[
+            kvs::Command::Begin,
+            cas("key1", Some("value3"), Some("value4")),
+            cas("key1", Some("value3"), Some("value5")),
+            kvs::Command::SetIfAbsent("key3".to_owned(), "value1".to_owned()),
+            kvs::Command::Commit,
+            kvs::Command::Get("key1".to_owned()),
+        ])
+        .unwrap();
+    assert_eq!(
+        responses,
+        vec![
+            Response::Null,
+            Response::Bool(true),
+            Response::Bool(false),
+            Response::Bool(true),
+            Response::Null,
+            Response::Value("value4".to_owned()),
+        ]
+    );
+
+    drop(conn);
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn conditional_writes_kvs_engine() {
+    conditional_writes("kvs", "127.0.0.1:4017");
+}
+
+#[test]
+fn conditional_writes_sled_engine() {
+    conditional_writes("sled", "127.0.0.1:4018");
+}
+
+fn counters(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+        command
+    };
+
+    client(&["incr", "hits"]).assert().success().stdout("1\n");
+    client(&["incr", "hits", "10"]).assert().success().stdout("11\n");
+    client(&["decr", "hits", "20"]).assert().success().stdout("-9\n");
+    client(&["incr", "hits", "-1"]).assert().success().stdout("-10\n");
+    client(&["get", "hits"]).assert().success().stdout("-10\n");
+    client(&["incr", "hits", "ten"]).assert().failure();
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["incr", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("Value is not an integer"));
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn counters_kvs_engine() {
+    counters("kvs", "127.0.0.1:4019");
+}
+
+#[test]
+fn counters_sled_engine() {
+    counters("sled", "127.0.0.1:4020");
+}
+
+fn expiring_keys(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+        command
+    };
+
+    client(&["set", "key1", "value1", "--ttl", "1"]).assert().success();
+    client(&["set", "key2", "value2", "--ttl", "1"]).assert().success();
+    client(&["set", "key3", "value3"]).assert().success();
+    client(&["set", "key4", "value4", "--ttl", "0"]).assert().failure();
+    client(&["set", "key4", "value4", "--ttl", "1", "--nx"]).assert().failure();
+
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+    client(&["ttl", "key1"]).assert().success().stdout(contains("0."));
+    client(&["ttl", "key3"]).assert().success().stdout("No expiry\n");
+    client(&["ttl", "key4"]).assert().success().stdout("Key not found\n");
+    client(&["persist", "key2"]).assert().success();
+    client(&["persist", "key4"])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["get", "key2"]).assert().success().stdout("value2\n");
+    client(&["get", "key3"]).assert().success().stdout("value3\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn expiring_keys_kvs_engine() {
+    expiring_keys("kvs", "127.0.0.1:4021");
+}
+
+#[test]
+fn expiring_keys_sled_engine() {
+    expiring_keys("sled", "127.0.0.1:4022");
+}
+
+fn scans(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+        command
+    };
+
+    client(&["batch", "set", "b", "2", "set", "a", "1", "set", "c", "3", "set", "ab", "4"])
+        .assert()
+        .success();
+    client(&["scan"])
+        .assert()
+        .success()
+        .stdout("a\t1\nab\t4\nb\t2\nc\t3\n");
+    client(&["scan", "--start", "ab", "--end", "c"])
+        .assert()
+        .success()
+        .stdout("ab\t4\nb\t2\n");
+    client(&["scan", "--prefix", "a"])
+        .assert()
+        .success()
+        .stdout("a\t1\nab\t4\n");
+    client(&["scan", "--limit", "1"]).assert().success().stdout("a\t1\n");
+    client(&["scan", "--prefix", "d"]).assert().success().stdout(is_empty());
+    client(&["scan", "--limit", "x"]).assert().failure();
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let request = ScanRequest {
+        limit: 2,
+        ..ScanRequest::default()
+    };
+    assert_eq!(
+        client.request(&kvs::Command::Scan(request.clone())).unwrap(),
+        Response::Page(
+            vec![("a".to_owned(), "1".to_owned()), ("ab".to_owned(), "4".to_owned())],
+            Some("b".to_owned())
+        )
+    );
+    let request = ScanRequest {
+        start: Some("b".to_owned()),
+        ..request
+    };
+    assert_eq!(
+        client.request(&kvs::Command::Scan(request.clone())).unwrap(),
+        Response::Page(
+            vec![("b".to_owned(), "2".to_owned()), ("c".to_owned(), "3".to_owned())],
+            None
+        )
+    );
+
+    client.request(&kvs::Command::Begin).unwrap();
+    assert_eq!(
+        client.request(&kvs::Command::Scan(request)).unwrap(),
+        Response::Error(ErrorCode::InvalidCommand.into())
+    );
+    client.request(&kvs::Command::Abort).unwrap();
+
+    drop(client);
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn scans_kvs_engine() {
+    scans("kvs", "127.0.0.1:4023");
+}
+
+#[test]
+fn scans_sled_engine() {
+    scans("sled", "127.0.0.1:4024");
+}
+
+fn listing(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+        command
+    };
+
+    client(&["count"]).assert().success().stdout("0\n");
+    client(&["batch", "set", "user:2", "b", "set", "user:1", "a", "set", "user:10", "c", "set", "team:1", "d"])
+        .assert()
+        .success();
+    client(&["count"]).assert().success().stdout("4\n");
+    client(&["keys"])
+        .assert()
+        .success()
+        .stdout("team:1\nuser:1\nuser:10\nuser:2\n");
+    client(&["keys", "user:?"])
+        .assert()
+        .success()
+        .stdout("user:1\nuser:2\n");
+    client(&["keys", "*:1*", "--limit", "2"])
+        .assert()
+        .success()
+        .stdout("team:1\nuser:1\n");
+    client(&["keys", "group:*"]).assert().success().stdout(is_empty());
+    client(&["exists", "user:1"]).assert().success().stdout("true\n");
+    client(&["exists", "user:3"]).assert().success().stdout("false\n");
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let request = KeysRequest {
+        pattern: "user:*".to_owned(),
+        start: None,
+        limit: 2,
+    };
+    assert_eq!(
+        client.request(&kvs::Command::Keys(request.clone())).unwrap(),
+        Response::Keys(vec!["user:1".to_owned(), "user:10".to_owned()], Some("user:2".to_owned()))
+    );
+    let request = KeysRequest {
+        start: Some("user:2".to_owned()),
+        ..request
+    };
+    assert_eq!(
+        client.request(&kvs::Command::Keys(request.clone())).unwrap(),
+        Response::Keys(vec!["user:2".to_owned()], None)
+    );
+
+    client.request(&kvs::Command::Begin).unwrap();
+    client.request(&kvs::Command::Rm("user:1".to_owned())).unwrap();
+    assert_eq!(
+        client.request(&kvs::Command::Exists("user:1".to_owned())).unwrap(),
+        Response::Bool(false)
+    );
+    assert_eq!(
+        client.request(&kvs::Command::Count).unwrap(),
+        Response::Error(ErrorCode::InvalidCommand.into())
+    );
+    client.request(&kvs::Command::Abort).unwrap();
+    assert_eq!(
+        client.request(&kvs::Command::Exists("user:1".to_owned())).unwrap(),
+        Response::Bool(true)
+    );
+
+    drop(client);
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn listing_kvs_engine() {
+    listing("kvs", "127.0.0.1:4025");
+}
+
+#[test]
+fn listing_sled_engine() {
+    listing("sled", "127.0.0.1:4026");
+}
+
+fn binary_values(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+        command
+    };
+
+    let blob: Vec<u8> = (0..=255).rev().collect();
+    fs::write(temp_dir.path().join("in.bin"), &blob).unwrap();
+    client(&["set", "blob", "--value-file", "in.bin"]).assert().success();
+    client(&["set", "blob", "--value-file", "in.bin", "--nx"]).assert().failure();
+    client(&["set", "blob", "value", "--value-file", "in.bin"]).assert().failure();
+    client(&["set", "other", "--value-file", "missing.bin"]).assert().failure();
+    client(&["get", "blob", "--value-file", "out.bin"]).assert().success();
+    assert_eq!(fs::read(temp_dir.path().join("out.bin")).unwrap(), blob);
+    client(&["get", "blob"]).assert().failure();
+    client(&["get", "other", "--value-file", "other.bin"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    assert!(!temp_dir.path().join("other.bin").exists());
+
+    fs::write(temp_dir.path().join("text.txt"), "value1").unwrap();
+    client(&["set", "key1", "--value-file", "text.txt"]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let key = Bytes(vec![0xff, 0x00]);
+    for value in &[vec![0xff], vec![0xfe, 0x01], vec![]] {
+        assert_eq!(
+            client
+                .request(&kvs::Command::SetBytes(key.clone(), Bytes(value.clone())))
+                .unwrap(),
+            Response::Null
+        );
+        assert_eq!(
+            client.request(&kvs::Command::GetBytes(key.clone())).unwrap(),
+            Response::Bytes(Bytes(value.clone()))
+        );
+    }
+    assert_eq!(
+        client.request(&kvs::Command::RmBytes(key.clone())).unwrap(),
+        Response::Null
+    );
+    assert_eq!(
+        client.request(&kvs::Command::GetBytes(key)).unwrap(),
+        Response::Error(ErrorCode::NotFound.into())
+    );
+
+    // listings go past a key that is not utf-8, leaving it and the binary value of "blob" out
+    let key = Bytes(vec![b'k', 0xff]);
+    client
+        .request(&kvs::Command::SetBytes(key.clone(), Bytes(b"binary".to_vec())))
+        .unwrap();
+    client
+        .request(&kvs::Command::Set("z".to_owned(), "last".to_owned()))
+        .unwrap();
+    assert_eq!(
+        client.request(&kvs::Command::ExistsBytes(key.clone())).unwrap(),
+        Response::Bool(true)
+    );
+    assert_eq!(
+        client.request(&kvs::Command::TtlBytes(key.clone())).unwrap(),
+        Response::Ttl(None)
+    );
+    assert_eq!(
+        client.request(&kvs::Command::PersistBytes(key)).unwrap(),
+        Response::Bool(false)
+    );
+    drop(client);
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]);
+        command
+    };
+    client(&["scan"])
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\nz\tlast\n");
+    client(&["scan", "--limit", "1", "--start", "key2"])
+        .assert()
+        .success()
+        .stdout("z\tlast\n");
+    client(&["keys"]).assert().success().stdout("blob\nkey1\nz\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn binary_values_kvs_engine() {
+    binary_values("kvs", "127.0.0.1:4027");
+}
+
+#[test]
+fn binary_values_sled_engine() {
+    binary_values("sled", "127.0.0.1:4028");
+}
+
+// A read-only server should serve what a writing server stored in the same directory, and reject writes
+#[test]
+fn cli_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut writer = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4031", "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4031"])
+        .assert()
+        .success();
+
+    let mut reader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4032", "--threads", "2", "--read-only"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4032"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--addr", "127.0.0.1:4032"])
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4031"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    reader.kill().expect("server exited before killed");
+    reader.wait().unwrap();
+    writer.kill().expect("server exited before killed");
+    writer.wait().unwrap();
+}
+
+// `kvs-client` should exit with a code telling the class of the error
+#[test]
+fn cli_error_exit_codes() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4033", "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str], addr: &str| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+        command
+    };
+
+    client(&["set", "key1", "value1"], "127.0.0.1:4033").assert().success();
+    client(&["rm", "key2"], "127.0.0.1:4033")
+        .assert()
+        .code(1)
+        .stderr(contains("Key not found"));
+    // the message of the server names the key
+    client(&["incr", "key1"], "127.0.0.1:4033")
+        .assert()
+        .code(2)
+        .stderr(contains("Value is not an integer"))
+        .stderr(contains("key1"));
+    client(&["get", "key1"], "127.0.0.1:4034")
+        .assert()
+        .code(6)
+        .stderr(contains("Cannot connect"));
+
+    let mut client = KvsClient::connect("127.0.0.1:4033").unwrap();
+    match client.request(&kvs::Command::Incr("key1".to_owned(), 1)).unwrap() {
+        Response::Error(error) => {
+            assert_eq!(error.code, ErrorCode::NotAnInteger);
+            assert!(!error.retryable);
+        }
+        response => panic!("expected an error, got {:?}", response),
+    }
+
+    // a frame that is no command is the client's fault
+    let mut stream = TcpStream::connect("127.0.0.1:4033").unwrap();
+    write_frame(&mut stream, &"not a command").unwrap();
+    match read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE).unwrap() {
+        Some(Response::Error(error)) => assert_eq!(error.code, ErrorCode::InvalidCommand),
+        response => panic!("expected an error, got {:?}", response),
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Idle connections should not keep a new client from being served, however few threads the pool has
+#[test]
+fn cli_idle_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4035", "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // one connection inside a transaction, the others without a request yet
+    let mut idle: Vec<KvsClient> = (0..4)
+        .map(|_| KvsClient::connect("127.0.0.1:4035").unwrap())
+        .collect();
+    assert_eq!(idle[0].request(&kvs::Command::Begin).unwrap(), Response::Null);
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut client = KvsClient::connect("127.0.0.1:4035").unwrap();
+        let set = kvs::Command::Set("key1".to_owned(), "value1".to_owned());
+        let _ = sender.send(client.request(&set).unwrap());
+    });
+    let response = receiver.recv_timeout(Duration::from_secs(5));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert_eq!(response, Ok(Response::Null));
+    drop(idle);
+}
+
+// Connections beyond the limit should wait to be served until an open one closes
+#[test]
+fn cli_max_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4036", "--max-connections", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut first = KvsClient::connect("127.0.0.1:4036").unwrap();
+    let set = kvs::Command::Set("key1".to_owned(), "value1".to_owned());
+    assert_eq!(first.request(&set).unwrap(), Response::Null);
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut client = KvsClient::connect("127.0.0.1:4036").unwrap();
+        let get = kvs::Command::Get("key1".to_owned());
+        let _ = sender.send(client.request(&get).unwrap());
+    });
+    let waiting = receiver.recv_timeout(Duration::from_secs(1));
+    drop(first);
+    let served = receiver.recv_timeout(Duration::from_secs(5));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert!(waiting.is_err(), "served beyond the limit: {:?}", waiting);
+    assert_eq!(served, Ok(Response::Value("value1".to_owned())));
+}
@@ -1,4 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{CompactionPolicy, Durability, Glob, KvStore, KvsEngine, KvsError, Result, WriteBatch};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +13,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +23,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +34,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +43,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +55,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +71,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +79,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +91,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +121,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -125,3 +132,1272 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+
+// Clones of one store used from many threads should all see the same data.
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}-{}", t, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}-{}", t, i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}-{}", t, i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Readers running alongside a writer should always see a complete value.
+#[test]
+fn concurrent_get_while_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 1..=20 {
+                for i in 0..100 {
+                    store.set(format!("key{}", i), format!("{}", iter)).unwrap();
+                }
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    for i in 0..100 {
+                        let value = store.get(format!("key{}", i)).unwrap().unwrap();
+                        let iter: u32 = value.parse().unwrap();
+                        assert!(iter <= 20);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("20".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Background compaction should neither lose writes nor disturb readers.
+#[test]
+fn compaction_while_reading() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                for key_id in (0..1000).step_by(7) {
+                    let value = store.get(format!("key{}", key_id)).unwrap();
+                    assert!(value.is_some(), "key{} vanished during compaction", key_id);
+                }
+            }
+        })
+    };
+
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    // the store starts with a single log, compaction adds two generations
+    let mut compacted = false;
+    for iter in 1..=700 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        compacted |= log_count() > 1;
+    }
+    done.store(true, Ordering::SeqCst);
+    reader.join().unwrap();
+    assert!(compacted, "No compaction detected");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("700".to_owned()));
+    }
+
+    Ok(())
+}
+
+// `compact` should shrink the logs right away, even with a manual-only policy.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_policy(CompactionPolicy::Manual)
+        .open()?;
+
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let before = dir_size(temp_dir.path());
+    store.compact()?;
+    assert!(dir_size(temp_dir.path()) < before / 10);
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A stale byte threshold should start a background compaction on its own.
+#[test]
+fn stale_bytes_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_policy(CompactionPolicy::StaleBytes(64 * 1024))
+        .open()?;
+
+    let mut peak = 0;
+    let mut compacted = false;
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        let size = dir_size(temp_dir.path());
+        compacted |= size < peak;
+        peak = peak.max(size);
+    }
+    drop(store);
+    assert!(compacted, "No compaction detected");
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn parse_compaction_policy() {
+    assert_eq!("manual".parse(), Ok(CompactionPolicy::Manual));
+    assert_eq!("bytes:1024".parse(), Ok(CompactionPolicy::StaleBytes(1024)));
+    assert_eq!(
+        "interval:60".parse(),
+        Ok(CompactionPolicy::Interval(Duration::from_secs(60)))
+    );
+    assert!(matches!(
+        "ratio:0.3".parse(),
+        Ok(CompactionPolicy::GarbageRatio { ratio, .. }) if ratio == 0.3
+    ));
+    assert!("ratio:2".parse::<CompactionPolicy>().is_err());
+    assert!("sometimes".parse::<CompactionPolicy>().is_err());
+}
+
+// Values with newlines and other control characters should survive a reopen
+#[test]
+fn value_with_control_characters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "line1\nline2\r\n\0\u{1}{\"SetRec\":[]}".to_owned();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), value.clone())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Open should refuse a log with a damaged record
+#[test]
+fn open_corrupted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // flip the last byte of "value2", the record after it shows it was not torn by a crash
+    let log_path = temp_dir.path().join("0.log");
+    let mut log = fs::read(&log_path)?;
+    let at = log.windows(6).position(|bytes| bytes == b"value2").unwrap();
+    log[at + 5] = b'3';
+    fs::write(&log_path, log)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corrupted(_))
+    ));
+    Ok(())
+}
+
+// The error of a damaged log should say which record of which log is damaged
+#[test]
+fn corrupted_log_error_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // flip the last byte of "value2", the record after it shows it was not torn by a crash
+    let log_path = temp_dir.path().join("0.log");
+    let mut log = fs::read(&log_path)?;
+    let at = log.windows(6).position(|bytes| bytes == b"value2").unwrap();
+    log[at + 5] = b'3';
+    fs::write(&log_path, log)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corrupted(corruption)) => {
+            assert_eq!(corruption.file, Path::new("0.log"));
+            // the first record is intact, the damaged one comes after it
+            assert!(corruption.offset > 0);
+        }
+        other => panic!("expected a corrupted log, got {:?}", other.err()),
+    }
+    Ok(())
+}
+
+// Open should refuse a path that is not a directory, without touching it
+#[test]
+fn open_file_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("file");
+    fs::write(&path, "content")?;
+
+    assert!(matches!(KvStore::open(&path), Err(KvsError::InvalidPath(..))));
+    assert!(matches!(KvStore::open_read_only(&path), Err(KvsError::InvalidPath(..))));
+    assert_eq!(fs::read_to_string(&path)?, "content");
+    Ok(())
+}
+
+// A json log written by an older version should be upgraded on open
+#[test]
+fn upgrade_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("0.log"),
+        "{\"SetRec\":[\"key1\",\"value1\"]}\n\
+         {\"SetRec\":[\"key2\",\"value2\"]}\n\
+         {\"RmRec\":\"key1\"}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    assert!(fs::read(temp_dir.path().join("0.log"))?.starts_with(b"KVSL"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A record torn at any byte should be cut off on open, keeping every record before it
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("0.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let intact = fs::read(&log_path)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let full = fs::read(&log_path)?;
+
+    for cut in intact.len()..full.len() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log_path = temp_dir.path().join("0.log");
+        fs::write(&log_path, &full[..cut])?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(fs::read(&log_path)?, intact);
+        if cut > intact.len() {
+            let quarantined = temp_dir.path().join(format!("0-{}.torn", intact.len()));
+            assert_eq!(fs::read(quarantined)?, &full[intact.len()..cut]);
+        }
+
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A tail of zeros or garbage the file grew by before a crash should be cut off like a torn record,
+// but a damaged record followed by a whole one is corruption
+#[test]
+fn recover_damaged_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("0.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let intact = fs::read(&log_path)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let second = fs::read(&log_path)?[intact.len()..].to_vec();
+
+    let mut garbage = vec![0xab; 64];
+    garbage[4..8].copy_from_slice(&20u32.to_le_bytes());
+    for tail in &[vec![0; 32], garbage.clone()] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log_path = temp_dir.path().join("0.log");
+        fs::write(&log_path, [intact.as_slice(), tail].concat())?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(fs::read(&log_path)?, intact);
+        let quarantined = temp_dir.path().join(format!("0-{}.torn", intact.len()));
+        assert_eq!(&fs::read(quarantined)?, tail);
+    }
+
+    fs::write(&log_path, [intact.as_slice(), &garbage, &second].concat())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corrupted(corruption)) => assert_eq!(corruption.offset, intact.len() as u64),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a corrupted log"),
+    }
+    Ok(())
+}
+
+// A log whose header was torn should open as an empty log
+#[test]
+fn recover_torn_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("0.log"), b"KVS")?;
+
+    let store = KvStore::builder(temp_dir.path())
+        .quarantine_torn_tail(false)
+        .open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("0-0.torn").exists());
+    Ok(())
+}
+
+// Compaction should leave hint files that open uses instead of the logs,
+// falling back to the logs when a hint is missing or damaged
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_policy(CompactionPolicy::Manual)
+        .open()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id + 1))?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "latest".to_owned())?;
+    drop(store);
+
+    let hints: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.expect("fail to list the store").into_path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    assert_eq!(hints.len(), 1);
+    let hint = fs::read(&hints[0])?;
+    assert!(!hint.windows(6).any(|w| w == b"value5"));
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("latest".to_owned()));
+        for key_id in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id + 1))
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    let mut damaged = hint.clone();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 0xff;
+    fs::write(&hints[0], damaged)?;
+    check()?;
+
+    fs::remove_file(&hints[0])?;
+    check()?;
+    Ok(())
+}
+
+// A write batch should apply in order and survive reopening and compaction
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_policy(CompactionPolicy::Manual)
+        .open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key3".to_owned());
+    batch.remove("key4".to_owned());
+    store.write_batch(batch)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// A batch torn anywhere should be dropped as a whole on open
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("0.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let intact = fs::read(&log_path)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value2".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+    let full = fs::read(&log_path)?;
+
+    for cut in intact.len()..full.len() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(temp_dir.path().join("0.log"), &full[..cut])?;
+
+        let store = KvStore::builder(temp_dir.path())
+            .quarantine_torn_tail(false)
+            .open()?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// A transaction should see its own writes and apply them on commit
+#[test]
+fn transaction_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.set("key2".to_owned(), "value2".to_owned());
+    txn.remove("key1".to_owned())?;
+    assert_eq!(txn.get("key1".to_owned())?, None);
+    assert_eq!(txn.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        txn.remove("key3".to_owned()),
+        Err(KvsError::NotFound(_))
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    txn.commit()?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut txn = store.begin();
+    txn.set("key3".to_owned(), "value3".to_owned());
+    txn.abort();
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A transaction should fail to commit if a key it read changed, even if it changed back to missing
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.begin();
+    txn.get("key1".to_owned())?;
+    txn.set("key2".to_owned(), "value2".to_owned());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict(ref key)) if key == "key1"));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key3".to_owned())?, None);
+    txn.set("key2".to_owned(), "value2".to_owned());
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict(_))));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Concurrent read-modify-write transactions retried on conflict should not lose updates
+#[test]
+fn transaction_no_lost_updates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let mut txn = store.begin();
+                        let counter: u64 = txn.get("counter".to_owned())?.unwrap().parse().unwrap();
+                        txn.set("counter".to_owned(), (counter + 1).to_string());
+                        match txn.commit() {
+                            Err(KvsError::Conflict(_)) => continue,
+                            result => break result?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Conditional writes should only take effect when their condition holds, also after a reopen
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_if_present("key1".to_owned(), "value1".to_owned())?);
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert!(store.set_if_present("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value3".to_owned())
+    )?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value2".to_owned()),
+        Some("value3".to_owned())
+    )?);
+    assert!(store.compare_and_swap("key2".to_owned(), None, Some("value1".to_owned()))?);
+    assert!(store.compare_and_swap("key2".to_owned(), Some("value1".to_owned()), None)?);
+    assert!(store.compare_and_swap("key2".to_owned(), None, None)?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, None)?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Concurrent compare-and-swap loops should not lose updates
+#[test]
+fn compare_and_swap_no_lost_updates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned())?;
+                        let counter: u64 = current.as_deref().map_or(0, |s| s.parse().unwrap());
+                        let new = Some((counter + 1).to_string());
+                        if store.compare_and_swap("counter".to_owned(), current, new)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Counters should start from 0, reject values that are not integers and report overflows
+#[test]
+fn incr_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.decr("counter".to_owned(), 7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.incr("key1".to_owned(), 1),
+        Err(KvsError::NotAnInteger(ref key)) if key == "key1"
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(store.incr("max".to_owned(), 1), Err(KvsError::Overflow(_))));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    // subtracting i64::MIN overflows only if the result does not fit
+    assert_eq!(store.decr("counter".to_owned(), i64::MIN)?, i64::MAX - 1);
+    store.set("minus".to_owned(), "-1".to_owned())?;
+    let mut txn = store.begin();
+    assert_eq!(txn.decr("minus".to_owned(), i64::MIN)?, i64::MAX);
+    txn.commit()?;
+    assert_eq!(store.get("minus".to_owned())?, Some(i64::MAX.to_string()));
+    assert!(matches!(store.decr("zero".to_owned(), i64::MIN), Err(KvsError::Overflow(_))));
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    store.incr("hits".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Keys set with a ttl should disappear once it passed, also across a restart
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ttl = Duration::from_millis(500);
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)?;
+    store.set_with_ttl("counter".to_owned(), "1".to_owned(), ttl)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(store.ttl("key1".to_owned())?, Some(left) if left <= ttl));
+    assert_eq!(store.ttl("key3".to_owned())?, None);
+    assert!(matches!(store.ttl("key4".to_owned()), Err(KvsError::NotFound(_))));
+
+    assert!(store.persist("key2".to_owned())?);
+    assert!(!store.persist("key2".to_owned())?);
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+    assert_eq!(store.incr("counter".to_owned(), 1)?, 2);
+    assert!(store.ttl("counter".to_owned())?.is_some());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.ttl("key1".to_owned())?.is_some());
+    thread::sleep(ttl);
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("counter".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::NotFound(_))));
+    assert!(!store.set_if_present("key1".to_owned(), "value4".to_owned())?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compaction should drop expired records, and a key set again after it expired should not come back
+#[test]
+fn ttl_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_policy(CompactionPolicy::Manual)
+        .open()?;
+
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        store.set(key.clone(), "0".repeat(1000))?;
+        store.set_with_ttl(key, "1".repeat(1000), Duration::from_millis(200))?;
+    }
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+
+    let before = dir_size(temp_dir.path());
+    store.compact()?;
+    assert!(dir_size(temp_dir.path()) < before / 10);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Scans should return the live keys of a range or prefix in order, also after a restart
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set_with_ttl("key5".to_owned(), "value5".to_owned(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+
+    let keys = |entries: Vec<(String, String)>| -> Vec<String> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+    let entries = store
+        .scan("key2".to_owned().."key7".to_owned(), usize::MAX)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries[0], ("key2".to_owned(), "value2".to_owned()));
+    assert_eq!(keys(entries), vec!["key2", "key4", "key6"]);
+    let entries = store.scan(.., 3).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(entries), vec!["key0", "key1", "key2"]);
+    let entries = store.scan("key8".to_owned().., usize::MAX).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(entries), vec!["key8", "key9", "other"]);
+    let entries = store.scan_prefix("key", usize::MAX).collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 8);
+    assert_eq!(store.scan_prefix("missing", 10).count(), 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let entries = store.scan_prefix("key", 2).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(entries), vec!["key0", "key1"]);
+    let entries = store.scan("key3".to_owned()..="key5".to_owned(), 10).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(entries), vec!["key4"]);
+    Ok(())
+}
+
+// Glob patterns should match whole keys
+#[test]
+fn glob_patterns() {
+    let matches = |pattern: &str, key: &str| Glob::new(pattern).matches(key);
+    assert!(matches("*", ""));
+    assert!(matches("user:*", "user:42"));
+    assert!(!matches("user:*", "users:42"));
+    assert!(matches("*:42", "user:42"));
+    assert!(matches("a*b*c", "aXbYbZc"));
+    assert!(!matches("a*b*c", "aXbYcZ"));
+    assert!(matches("h?llo", "hello"));
+    assert!(!matches("h?llo", "hllo"));
+    assert!(matches("h[ae]llo", "hallo"));
+    assert!(!matches("h[ae]llo", "hillo"));
+    assert!(matches("key[0-9]", "key7"));
+    assert!(matches("key[!0-9]", "keyx"));
+    assert!(!matches("key[^0-9]", "key7"));
+    assert!(matches("a\\*", "a*"));
+    assert!(!matches("a\\*", "ab"));
+    assert!(matches("[]]", "]"));
+    assert!(matches("a[b", "a[b"));
+
+    assert_eq!(Glob::new("user:*:name").literal_prefix(), "user:");
+    assert_eq!(Glob::new("a\\?b*").literal_prefix(), "a?b");
+    assert_eq!(Glob::new("*").literal_prefix(), "");
+}
+
+// Keys, counts and existence checks should only see live keys
+#[test]
+fn keys_and_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..12 {
+        store.set(format!("user:{}", key_id), "name".to_owned())?;
+    }
+    store.set("session:1".to_owned(), "token".to_owned())?;
+    store.remove("user:3".to_owned())?;
+    store.set_with_ttl("user:4".to_owned(), "name".to_owned(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+
+    assert!(store.exists("user:1".to_owned())?);
+    assert!(!store.exists("user:3".to_owned())?);
+    assert!(!store.exists("user:4".to_owned())?);
+    assert_eq!(store.count()?, 11);
+
+    let keys = store.keys_matching(&Glob::new("user:1*"), usize::MAX).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["user:1", "user:10", "user:11"]);
+    let keys = store.keys_matching(&Glob::new("*:1"), usize::MAX).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["session:1", "user:1"]);
+    let keys = store.keys_matching(&Glob::new("user:?"), 3).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["user:0", "user:1", "user:2"]);
+    let keys = store.keys("user:5".to_owned()..="user:7".to_owned(), 10).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["user:5", "user:6", "user:7"]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 11);
+    assert!(!store.exists("user:4".to_owned())?);
+    Ok(())
+}
+
+// Keys and values that are not utf-8 should be stored as they are, also across compaction and a restart
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_policy(CompactionPolicy::Manual)
+        .open()?;
+
+    let key = vec![0xff, 0x00, b'k'];
+    let value = vec![0xfe, 0xff, 0x00, b'\n', 0x80];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(b"text".to_vec(), b"value".to_vec())?;
+    store.set_bytes(vec![0xc3], vec![])?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get_bytes(vec![0xc3])?, Some(vec![]));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_bytes(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+    store.set_bytes(b"blob".to_vec(), value.clone())?;
+    assert!(matches!(store.get("blob".to_owned()), Err(KvsError::FromUtf8Error(_))));
+    assert!(matches!(store.incr("blob".to_owned(), 1), Err(KvsError::NotAnInteger(_))));
+    assert!(!store.compare_and_swap("blob".to_owned(), None, None)?);
+    store.remove_bytes(b"blob".to_vec())?;
+    assert!(matches!(store.remove_bytes(b"blob".to_vec()), Err(KvsError::NotFound(_))));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value));
+    assert_eq!(store.get_bytes(b"blob".to_vec())?, None);
+    assert_eq!(store.get_bytes(vec![0xc3])?, Some(vec![]));
+    assert_eq!(store.count()?, 4);
+
+    // string listings leave out what is not utf-8, byte listings do not
+    let keys: Vec<String> = store.keys(.., usize::MAX).collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key1", "text"]);
+    let keys: Vec<Vec<u8>> = store.keys_bytes(.., usize::MAX).collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"key1".to_vec(), b"text".to_vec(), vec![0xc3], key.clone()]);
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = store.scan_bytes(vec![0xc3].., 1).collect::<Result<_>>()?;
+    assert_eq!(entries, vec![(vec![0xc3], vec![])]);
+    assert!(store.exists_bytes(key.clone())?);
+    assert_eq!(store.ttl_bytes(key)?, None);
+    Ok(())
+}
+
+// Every durability setting should keep the written values across a reopen
+#[test]
+fn durability_settings() -> Result<()> {
+    for &durability in &[
+        Durability::Always,
+        Durability::EveryN(3),
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::OsBuffered,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder(temp_dir.path())
+            .durability(durability)
+            .open()?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        thread::sleep(Duration::from_millis(20));
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Concurrent writers with group commit should all be acknowledged and durable
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .durability(Durability::Always)
+        .group_commit(Duration::from_millis(2))
+        .open()?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key.clone(), format!("value{}", key_id))?;
+                    if key_id % 2 == 0 {
+                        store.remove(key)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert!(matches!(
+        store.remove("key0-0".to_owned()),
+        Err(KvsError::NotFound(_))
+    ));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..100 {
+            let expected = if key_id % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}", key_id))
+            };
+            assert_eq!(store.get(format!("key{}-{}", thread_id, key_id))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// A full active log should be sealed for a new one, and a background compaction should merge
+// only the logs with the most garbage, without bringing back keys the merged logs removed
+#[test]
+fn segment_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |policy| {
+        KvStore::builder(temp_dir.path())
+            .compaction_policy(policy)
+            .max_segment_size(16 * 1024)
+            .open()
+    };
+    let value = "v".repeat(100);
+
+    let store = open(CompactionPolicy::Manual)?;
+    for key_id in 0..1000 {
+        store.set(format!("cold{}", key_id), value.clone())?;
+    }
+    let sealed = log_gens(temp_dir.path());
+    assert!(sealed.len() > 5, "the log was not rotated");
+    drop(store);
+
+    // the remove and the expiring set land among the hot writes, in logs that get merged
+    let store = open(CompactionPolicy::StaleBytes(64 * 1024))?;
+    for iter in 0..200 {
+        if iter == 100 {
+            store.remove("cold0".to_owned())?;
+            store.set_with_ttl("cold1".to_owned(), value.clone(), Duration::from_millis(1))?;
+            thread::sleep(Duration::from_millis(10));
+        }
+        for key_id in 0..10 {
+            store.set(format!("hot{}", key_id), format!("{}{}", value, iter))?;
+        }
+    }
+    drop(store);
+
+    // the logs holding nothing but live cold keys are left alone
+    let gens = log_gens(temp_dir.path());
+    assert_eq!(gens[..sealed.len() - 1], sealed[..sealed.len() - 1]);
+    assert!(dir_size(temp_dir.path()) < 300 * 1024, "the hot logs were not merged");
+
+    let store = open(CompactionPolicy::Manual)?;
+    assert_eq!(store.get("cold0".to_owned())?, None);
+    assert_eq!(store.get("cold1".to_owned())?, None);
+    for key_id in 2..1000 {
+        assert_eq!(store.get(format!("cold{}", key_id))?, Some(value.clone()));
+    }
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("hot{}", key_id))?, Some(format!("{}199", value)));
+    }
+
+    Ok(())
+}
+
+// Background compactions of a store rewriting the same keys should keep the logs about as small as the live data,
+// without piling up logs that hold nothing live
+#[test]
+fn compaction_churn_stays_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_policy(CompactionPolicy::StaleBytes(64 * 1024))
+        .max_segment_size(256 * 1024)
+        .open()?;
+    let value = "v".repeat(200);
+
+    for round in 0..5 {
+        for iter in 0..200 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+            }
+        }
+        // the compaction started by the last writes may still be running
+        thread::sleep(Duration::from_millis(200));
+        let logs = log_gens(temp_dir.path()).len();
+        let size = dir_size(temp_dir.path());
+        assert!(logs <= 6, "{} logs after round {}", logs, round);
+        assert!(size < 1024 * 1024, "{} bytes after round {}", size, round);
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{}199", value)));
+    }
+    Ok(())
+}
+
+// A directory should only be open in one store at a time
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked(path, pid)) => {
+            assert_eq!(path, temp_dir.path());
+            assert_eq!(pid, Some(std::process::id()));
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a locked store"),
+    }
+
+    // dropping the store releases the lock
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // the lock is only released once the background threads stopped, and then at once
+    for i in 0..20 {
+        let store = KvStore::builder(temp_dir.path())
+            .compaction_policy(CompactionPolicy::Interval(Duration::from_millis(1)))
+            .durability(Durability::Interval(Duration::from_millis(1)))
+            .group_commit(Duration::from_millis(1))
+            .open()?;
+        let clone = store.clone();
+        let handle = thread::spawn(move || clone.set(format!("key{}", i), "value".to_owned()));
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        drop(store);
+        handle.join().unwrap()?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+    assert_eq!(store.get("key19".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A read-only store should see the data of a store open for writing, without changing anything on disk
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    // a record the writer is in the middle of
+    let active = temp_dir.path().join(format!("{}.log", log_gens(temp_dir.path()).last().unwrap()));
+    OpenOptions::new().append(true).open(&active)?.write_all(&[0, 1, 2])?;
+
+    let files = || {
+        let mut files: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path().to_owned(), entry.metadata().unwrap().len())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files();
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert_eq!(reader.count()?, 1);
+    assert!(matches!(reader.set("key3".to_owned(), "value3".to_owned()), Err(KvsError::ReadOnly(_))));
+    assert!(matches!(reader.remove("key1".to_owned()), Err(KvsError::ReadOnly(_))));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly(_))));
+
+    let mut txn = reader.begin();
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.commit()?;
+
+    drop(reader);
+    assert_eq!(files(), before);
+
+    Ok(())
+}
+
+#[test]
+fn parse_durability() {
+    assert_eq!("always".parse(), Ok(Durability::Always));
+    assert_eq!("os".parse(), Ok(Durability::OsBuffered));
+    assert_eq!("every:100".parse(), Ok(Durability::EveryN(100)));
+    assert_eq!(
+        "interval:50".parse(),
+        Ok(Durability::Interval(Duration::from_millis(50)))
+    );
+    assert!("every:0".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let entries = WalkDir::new(path).into_iter();
+    let len: walkdir::Result<u64> = entries
+        .map(|res| {
+            res.and_then(|entry| entry.metadata())
+                .map(|metadata| metadata.len())
+        })
+        .sum();
+    len.expect("fail to get directory size")
+}
+
+/// the generations of the logs in `path`, in ascending order
+fn log_gens(path: &Path) -> Vec<u64> {
+    let mut gens: Vec<u64> = fs::read_dir(path)
+        .expect("fail to list the logs")
+        .map(|entry| entry.expect("fail to list the logs").path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+        .collect();
+    gens.sort_unstable();
+    gens
+}